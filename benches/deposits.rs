@@ -0,0 +1,60 @@
+//! Measures deposit throughput of `Transaction::append_to`.
+//!
+//! Run with `cargo bench --bench deposits`. Uses a plain timing loop
+//! rather than the unstable `#[bench]` harness, so only the best of a few
+//! runs is reported.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use toy_ledger::{
+    ledger::Ledger,
+    transaction::{Transaction, TransactionType},
+};
+
+const DEPOSITS: u32 = 1_000_000;
+const CLIENTS: u32 = 1_000;
+const RUNS: usize = 15;
+
+fn deposits() -> Vec<Transaction> {
+    (0..DEPOSITS)
+        .map(|tx_id| Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: (tx_id % CLIENTS) as u16,
+            tx_id,
+            amount: Some(1.5),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        })
+        .collect()
+}
+
+fn run(deposits: &[Transaction]) -> Duration {
+    // Sized up front so that growing the map isn't measured.
+    let mut ledger = Ledger::new(HashMap::with_capacity(deposits.len()), BTreeMap::new());
+    let start = Instant::now();
+
+    for deposit in deposits {
+        black_box(deposit.append_to(&mut ledger)).unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    let deposits = deposits();
+    let best = (0..RUNS).map(|_| run(&deposits)).min().unwrap();
+
+    println!(
+        "{} deposits in {:?}, {:.0} deposits/s",
+        DEPOSITS,
+        best,
+        DEPOSITS as f64 / best.as_secs_f64()
+    );
+}
@@ -1,7 +1,50 @@
-use serde::{Deserialize, Serialize};
+use alloc::{
+    format,
+    string::{String, ToString},
+};
 
-// 27 bytes
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Whether an account can transact and, if not, why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum AccountStatus {
+    /// The account can transact.
+    Active,
+
+    /// The account was frozen by a chargeback.
+    Locked,
+
+    /// The account was frozen for going negative, see
+    /// `LedgerOptions::freeze_on_negative`.
+    Frozen,
+
+    /// The account was administratively suspended.
+    Suspended,
+
+    /// The account was closed.
+    Closed,
+}
+
+/// How funds are rounded to a number of decimal places, see
+/// `LedgerOptions::precision`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Halves round away from zero, eg. `0.125` to `0.13`.
+    #[default]
+    HalfAwayFromZero,
+
+    /// Halves round to the even digit, eg. `0.125` to `0.12`, so that
+    /// rounding many halves doesn't drift in one direction.
+    HalfEven,
+
+    /// Extra places are dropped, eg. `0.129` to `0.12`.
+    TowardZero,
+}
+
+// 43 bytes
+#[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct Account {
     /// Client ID.
     #[serde(rename = "client")]
@@ -13,11 +56,40 @@ pub struct Account {
     #[serde(rename = "held")]
     pub held_funds: f64, // 8 bytes
 
-    #[serde(rename = "total")]
-    pub total_funds: f64, // 8 bytes
+    /// Serialized as the `locked` column, which is `true` for anything
+    /// other than `Active`. A `locked` account read back in is `Locked`.
+    #[serde(rename = "locked", deserialize_with = "status_from_locked")]
+    pub status: AccountStatus, // 1 bytes
 
-    #[serde(rename = "locked")]
-    pub is_locked: bool, // 1 bytes
+    /// Funds owed by the client which could not be taken from their
+    /// available funds. Only used when shortfall tracking is enabled,
+    /// otherwise the deficit is reflected as negative available funds.
+    #[serde(skip)]
+    pub shortfall: f64, // 8 bytes
+
+    /// ID of the chargeback which locked the account, if it was locked
+    /// by one.
+    #[serde(skip)]
+    pub locked_by_tx: Option<u32>, // 8 bytes
+
+    /// Number of the client's transactions currently disputed.
+    #[serde(skip)]
+    pub open_disputes: u32, // 4 bytes
+
+    /// Number of deposits and withdrawals applied to the account.
+    #[serde(skip)]
+    pub transaction_count: u32, // 4 bytes
+}
+
+/// An account's balances at a point in time, eg. just after a
+/// transaction was applied to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccountSnapshot {
+    pub client_id: u16,
+    pub available_funds: f64,
+    pub held_funds: f64,
+    pub total_funds: f64,
+    pub status: AccountStatus,
 }
 
 impl Account {
@@ -26,8 +98,131 @@ impl Account {
             client_id: id,
             held_funds: 0.0,
             available_funds: 0.0,
-            total_funds: 0.0,
-            is_locked: false,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+        }
+    }
+
+    /// Whether the account is prevented from transacting.
+    pub fn is_locked(&self) -> bool {
+        self.status != AccountStatus::Active
+    }
+
+    /// Total funds of the client. This is derived rather than stored
+    /// so that it can never drift from the available and held funds.
+    pub fn total(&self) -> f64 {
+        self.available_funds + self.held_funds - self.shortfall
+    }
+
+    /// Copies the account's current balances.
+    pub fn snapshot(&self) -> AccountSnapshot {
+        AccountSnapshot {
+            client_id: self.client_id,
+            available_funds: self.available_funds,
+            held_funds: self.held_funds,
+            total_funds: self.total(),
+            status: self.status,
         }
     }
+
+    /// Rounds the funds to `precision` decimal places.
+    pub fn round_funds(&mut self, precision: u32, rounding: Rounding) {
+        let round = |funds: f64| {
+            let scale = libm::pow(10.0, precision as f64);
+            let scaled = funds * scale;
+            let rounded = match rounding {
+                Rounding::HalfAwayFromZero => libm::round(scaled),
+                // `rint` rounds halves to even in the default float
+                // environment.
+                Rounding::HalfEven => libm::rint(scaled),
+                Rounding::TowardZero => libm::trunc(scaled),
+            };
+            // Adding zero turns a rounded `-0.0` into `0.0`.
+            rounded / scale + 0.0
+        };
+
+        self.available_funds = round(self.available_funds);
+        self.held_funds = round(self.held_funds);
+        self.shortfall = round(self.shortfall);
+    }
+
+    /// Settles the balances after the available funds change.
+    ///
+    /// When `track_shortfall` is set a negative available balance is
+    /// clamped at zero with the deficit moved into `shortfall`, and any
+    /// positive available balance first pays down an existing shortfall.
+    pub fn rebalance(&mut self, track_shortfall: bool) {
+        if !track_shortfall {
+            return;
+        }
+
+        let net = self.available_funds - self.shortfall;
+
+        if net < 0.0 {
+            self.available_funds = 0.0;
+            self.shortfall = -net;
+        } else {
+            self.available_funds = net;
+            self.shortfall = 0.0;
+        }
+    }
+
+    /// Compares the funds within `epsilon`, as the derived `PartialEq`
+    /// compares them exactly. The IDs must match and the accounts must be
+    /// both locked or both not, a looser check than `accounts_match` makes
+    /// of the status, so an account read back from the output compares
+    /// equal.
+    pub fn approx_eq(&self, other: &Account, epsilon: f64) -> bool {
+        self.client_id == other.client_id
+            && self.is_locked() == other.is_locked()
+            && libm::fabs(self.available_funds - other.available_funds) <= epsilon
+            && libm::fabs(self.held_funds - other.held_funds) <= epsilon
+            && libm::fabs(self.shortfall - other.shortfall) <= epsilon
+    }
+}
+
+fn status_from_locked<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<AccountStatus, D::Error> {
+    Ok(if bool::deserialize(deserializer)? {
+        AccountStatus::Locked
+    } else {
+        AccountStatus::Active
+    })
+}
+
+impl Serialize for Account {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Account", 5)?;
+        state.serialize_field("client", &self.client_id)?;
+        state.serialize_field("available", &self.available_funds)?;
+        state.serialize_field("held", &self.held_funds)?;
+        state.serialize_field("total", &self.total())?;
+        state.serialize_field("locked", &self.is_locked())?;
+        state.end()
+    }
+}
+
+/// Writes funds as text, to `precision` places if given or else as the
+/// shortest decimal which reads back as the same value, eg. `100.0` or
+/// `0.30000000000000004`. Both use a formatter whose output doesn't depend
+/// on the platform, Ryu for the shortest.
+///
+/// This is how funds are written as text everywhere. The CSV serializer
+/// writes `f64` fields with Ryu itself, so matches this. serde_json does
+/// too, other than writing a `+` in positive exponents, eg. `1e+21`.
+pub fn format_funds(funds: f64, precision: Option<u32>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision as usize, funds),
+        None => ryu::Buffer::new().format(funds).to_string(),
+    }
+}
+
+/// Compares two accounts as `Account::approx_eq` does, but the statuses
+/// must match exactly rather than just whether they're locked.
+pub fn accounts_match(left: &Account, right: &Account, epsilon: f64) -> bool {
+    left.approx_eq(right, epsilon) && left.status == right.status
 }
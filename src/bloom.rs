@@ -0,0 +1,72 @@
+use alloc::{vec, vec::Vec};
+
+/// A set of transaction IDs which takes a fixed amount of memory however
+/// many are added, at the cost of sometimes claiming to hold an ID which
+/// was never added. IDs added are never missed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes a filter to hold `capacity` IDs while wrongly claiming to
+    /// hold an ID at most `false_positive_rate` of the time, eg. `0.001`.
+    /// Adding more than `capacity` IDs raises the rate.
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+
+        let bit_count = libm::ceil(
+            -capacity * libm::log(rate) / (core::f64::consts::LN_2 * core::f64::consts::LN_2),
+        );
+        let words = (bit_count as usize).div_ceil(64).max(1);
+        let hashes = libm::round((words * 64) as f64 / capacity * core::f64::consts::LN_2).max(1.0);
+
+        BloomFilter {
+            bits: vec![0; words],
+            hashes: hashes as u32,
+        }
+    }
+
+    /// Adds `tx_id`, returning whether the filter might already have held
+    /// it. `true` may be a false positive.
+    pub fn insert(&mut self, tx_id: u32) -> bool {
+        let mut present = true;
+
+        for bit in self.bit_indexes(tx_id) {
+            let (word, mask) = (bit / 64, 1 << (bit % 64));
+            present &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+
+        present
+    }
+
+    /// Whether the filter might hold `tx_id`. `true` may be a false
+    /// positive, `false` never is.
+    pub fn contains(&self, tx_id: u32) -> bool {
+        self.bit_indexes(tx_id)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Bits set for `tx_id`, derived from two hashes of it rather than
+    /// `hashes` independent ones, which is as good for a bloom filter.
+    fn bit_indexes(&self, tx_id: u32) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        let first = mix(tx_id as u64);
+        let second = mix(first) | 1;
+
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+/// Scrambles `value` so that nearby IDs set unrelated bits, see
+/// SplitMix64.
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
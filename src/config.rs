@@ -0,0 +1,88 @@
+use std::{error::Error, path::Path};
+
+use serde::Deserialize;
+
+use crate::{ErrorFormat, OutputFormat, RoundingMode, MAX_AMOUNT_SCALE};
+
+/// Name of the config file picked up from the current directory
+/// when `--config` isn't given.
+const DEFAULT_CONFIG_FILENAME: &str = "ledger.toml";
+
+/// Defaults for command line flags, read from a TOML file.
+/// Keys match the long flag names, eg.
+///
+/// ```toml
+/// format = "json"
+/// pretty = true
+/// track-shortfall = true
+/// precision = 4
+/// rounding = "half-even"
+/// log-rejected = true
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub format: Option<OutputFormat>,
+    pub pretty: bool,
+    pub track_shortfall: bool,
+    pub reject_uncovered_chargeback: bool,
+    pub precision: Option<u32>,
+    pub rounding: Option<RoundingMode>,
+    pub delimiter: Option<char>,
+    pub amount_scale: Option<u32>,
+    pub log_rejected: bool,
+    pub error_format: Option<ErrorFormat>,
+    pub allow_deposit_when_locked: bool,
+    pub max_open_disputes: Option<u32>,
+    pub lenient_resolve: bool,
+    pub prune_after: Option<usize>,
+    pub verify_chargebacks: bool,
+    pub max_tx_per_client: Option<u32>,
+    pub min_balance: Option<f64>,
+    pub max_amount: Option<f64>,
+    pub freeze_on_negative: bool,
+    pub strict_dispute_amounts: bool,
+    pub allow_close: bool,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&contents)?;
+
+        // Checked as the command line flags are.
+        if config
+            .delimiter
+            .is_some_and(|delimiter| !delimiter.is_ascii())
+        {
+            return Err("delimiter must be an ASCII character".into());
+        }
+
+        if config
+            .amount_scale
+            .is_some_and(|scale| scale > MAX_AMOUNT_SCALE)
+        {
+            return Err(format!("amount-scale must be at most {}", MAX_AMOUNT_SCALE).into());
+        }
+
+        Ok(config)
+    }
+
+    /// Loads the config file at `path`, or `ledger.toml` in the current
+    /// directory if no path is given. A missing default file is not an
+    /// error and yields the default config.
+    pub fn discover(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => {
+                let path = Path::new(DEFAULT_CONFIG_FILENAME);
+
+                if path.exists() {
+                    Self::from_file(path)
+                } else {
+                    Ok(Self::default())
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use toy_ledger::{
+    account::{format_funds, Account, AccountSnapshot},
+    ledger::Ledger,
+    transaction::{AmountFormat, Transaction, TransactionError},
+};
+
+/// Applies `transaction` to the ledger, also returning a trace of its
+/// effect on the client's account if it is, or refers to, transaction
+/// `tx_id`. Disputes, resolves and chargebacks carry the ID of the
+/// transaction they refer to, so every step of its life is traced.
+///
+/// Funds are traced as they're written in the output, scaled back by
+/// `format` and to `precision` places.
+pub fn apply_explained(
+    transaction: &Transaction,
+    ledger: &mut Ledger,
+    tx_id: Option<u32>,
+    format: AmountFormat,
+    precision: Option<u32>,
+) -> (Result<AccountSnapshot, TransactionError>, Option<String>) {
+    if tx_id != Some(transaction.tx_id) {
+        return (transaction.append_to(ledger), None);
+    }
+
+    let client_id = transaction.client_id;
+    let snapshot = |ledger: &Ledger| {
+        ledger
+            .accounts
+            .get(&client_id)
+            .cloned()
+            .unwrap_or_else(|| Account::new(client_id))
+    };
+
+    let before = snapshot(ledger);
+    let result = transaction.append_to(ledger);
+    let after = snapshot(ledger);
+
+    let funds = |funds: f64| format_funds(format.rescale(funds), precision);
+    let trace = trace(transaction, &before, &after, &result, funds);
+    (result, Some(trace))
+}
+
+/// Describes the change `transaction` made to an account, eg.
+///
+/// ```text
+/// tx 1 dispute by client 1: applied
+///   available: 100.0 -> 0.0
+///   held: 0.0 -> 100.0
+///   total: 100.0 -> 100.0
+/// ```
+fn trace(
+    transaction: &Transaction,
+    before: &Account,
+    after: &Account,
+    result: &Result<AccountSnapshot, TransactionError>,
+    funds: impl Fn(f64) -> String,
+) -> String {
+    let outcome = match result {
+        Ok(_) => "applied".to_string(),
+        Err(err) => format!("rejected ({})", err),
+    };
+
+    format!(
+        "tx {} {} by client {}: {}\n  available: {} -> {}\n  held: {} -> {}\n  total: {} -> {}\n",
+        transaction.tx_id,
+        transaction.tx_type.name(),
+        transaction.client_id,
+        outcome,
+        funds(before.available_funds),
+        funds(after.available_funds),
+        funds(before.held_funds),
+        funds(after.held_funds),
+        funds(before.total()),
+        funds(after.total()),
+    )
+}
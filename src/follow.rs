@@ -0,0 +1,112 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use toy_ledger::ledger::Ledger;
+
+/// Splits the bytes appended to a growing CSV file into documents of
+/// complete rows, each prefixed with the file's header row so it can be
+/// parsed on its own, along with a `#schema=` line before it. A partial
+/// trailing line is held back until the rest of it arrives. Quoted fields
+/// containing newlines aren't supported.
+#[derive(Debug, Default)]
+pub struct FollowBuffer {
+    header: Option<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl FollowBuffer {
+    /// Adds newly read bytes, returning a document of any rows completed.
+    pub fn push(&mut self, bytes: &[u8]) -> Option<Vec<u8>> {
+        self.pending.extend_from_slice(bytes);
+
+        let end = self.pending.iter().rposition(|&byte| byte == b'\n')? + 1;
+        let mut rows: Vec<u8> = self.pending.drain(..end).collect();
+
+        let header = match &self.header {
+            Some(header) => header,
+            None => {
+                let Some(header_end) = header_len(&rows) else {
+                    // Wait for the header row after the schema marker.
+                    rows.append(&mut self.pending);
+                    self.pending = rows;
+                    return None;
+                };
+
+                self.header = Some(rows.drain(..header_end).collect());
+                self.header.as_ref()?
+            }
+        };
+
+        if rows.is_empty() {
+            return None;
+        }
+
+        let mut document = header.clone();
+        document.append(&mut rows);
+
+        Some(document)
+    }
+}
+
+/// Length of the header row at the start of `rows`, including a
+/// `#schema=` line before it, or `None` if it isn't complete.
+fn header_len(rows: &[u8]) -> Option<usize> {
+    let line_len = |rows: &[u8]| {
+        rows.iter()
+            .position(|&byte| byte == b'\n')
+            .map(|end| end + 1)
+    };
+    let first = line_len(rows)?;
+
+    if !rows.starts_with(b"#schema=") {
+        return Some(first);
+    }
+
+    Some(first + line_len(&rows[first..])?)
+}
+
+/// Processes the file at `path` like `tail -f`, passing rows to `apply`
+/// as they're appended. Every `interval` the file is checked for new rows
+/// and, if there are any, `apply` is called with a document of them, as
+/// from a `FollowBuffer`, and then `emit` with the ledger. Returns once
+/// `stop` is set, which is checked every `interval`, or if reading or
+/// `apply` fails.
+pub fn follow<A, F>(
+    path: &Path,
+    ledger: &mut Ledger,
+    interval: Duration,
+    stop: &AtomicBool,
+    mut apply: A,
+    mut emit: F,
+) -> Result<(), Box<dyn Error>>
+where
+    A: FnMut(&mut Ledger, &[u8]) -> Result<(), Box<dyn Error>>,
+    F: FnMut(&mut Ledger),
+{
+    let mut file = File::open(path)?;
+    let mut position = 0;
+    let mut buffer = FollowBuffer::default();
+    let mut bytes = vec![];
+
+    while !stop.load(Ordering::SeqCst) {
+        file.seek(SeekFrom::Start(position))?;
+        bytes.clear();
+        position += file.read_to_end(&mut bytes)? as u64;
+
+        if let Some(document) = buffer.push(&bytes) {
+            apply(ledger, &document)?;
+            emit(ledger);
+        }
+
+        thread::sleep(interval);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::io::Write;
+
+/// SplitMix64, small and fast with a fixed algorithm so that a seed
+/// produces the same file on every platform and release.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Writes `count` random but syntactically valid transactions for clients
+/// `1..=clients` as CSV. The same seed always produces the same output.
+///
+/// Roughly 60% of rows are deposits, 30% withdrawals and 10% disputes.
+/// Disputes always reference an earlier deposit or withdrawal of the same
+/// client, though withdrawals may still exceed the client's funds.
+pub fn generate<W: Write>(
+    writer: W,
+    count: usize,
+    clients: u16,
+    seed: u64,
+) -> Result<(), csv::Error> {
+    let mut rng = Rng(seed);
+    let mut wtr = csv::Writer::from_writer(writer);
+    // Transaction IDs of each client's deposits and withdrawals.
+    let mut history: Vec<Vec<u32>> = vec![vec![]; clients as usize];
+    let mut next_tx_id: u32 = 1;
+
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+
+    for _ in 0..count {
+        let client_index = rng.below(clients as u64) as usize;
+        let client_id = (client_index + 1).to_string();
+        let roll = rng.below(10);
+
+        if roll == 0 && !history[client_index].is_empty() {
+            let txs = &history[client_index];
+            let tx_id = txs[rng.below(txs.len() as u64) as usize];
+
+            wtr.write_record(["dispute", &client_id, &tx_id.to_string(), ""])?;
+            continue;
+        }
+
+        let tx_type = if roll < 7 { "deposit" } else { "withdrawal" };
+        let amount = format!("{:.4}", rng.below(10_000_000) as f64 / 10_000.0);
+
+        wtr.write_record([tx_type, &client_id, &next_tx_id.to_string(), &amount])?;
+        history[client_index].push(next_tx_id);
+        next_tx_id += 1;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
@@ -1,17 +1,636 @@
-use std::collections::HashMap;
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Debug;
 
-use crate::{account::Account, transaction::Transaction};
-#[derive(Debug)]
+#[cfg(feature = "std")]
+use std::io::Read;
+
+#[cfg(feature = "std")]
+use crate::transaction::{AmountFormat, TransactionReader};
+use crate::{
+    account::{accounts_match, Account, AccountStatus, Rounding},
+    bloom::BloomFilter,
+    transaction::{Transaction, TransactionError, TransactionType},
+    Map,
+};
+
+/// Tolerance when comparing held funds against the disputed amounts,
+/// since they are summed in a different order.
+const RECONCILE_EPSILON: f64 = 1e-9;
+
+/// Policies which change how transactions are applied to accounts.
+/// The defaults match the behaviour described in the README.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LedgerOptions {
+    /// Clamp available funds at zero when a dispute would drive them
+    /// negative and record the deficit in `Account::shortfall` instead.
+    pub track_shortfall: bool,
+
+    /// Refuse to chargeback a deposit when the client has already spent
+    /// the disputed funds, rather than driving their balance negative.
+    pub reject_uncovered_chargeback: bool,
+
+    /// Decimal places funds are rounded to after disputes, resolves and
+    /// chargebacks, so that float residuals don't accumulate in held funds.
+    /// `None` leaves funds unrounded.
+    pub precision: Option<u32>,
+
+    /// How funds are rounded to `precision` places.
+    pub rounding: Rounding,
+
+    /// Accept deposits into accounts locked by a chargeback. Everything
+    /// else is still refused, so funds can be paid in but not taken out.
+    pub allow_deposit_when_locked: bool,
+
+    /// Refuse new disputes from a client who already has this many
+    /// transactions disputed. `None` allows any number.
+    pub max_open_disputes: Option<u32>,
+
+    /// Ignore resolves and chargebacks of transactions which are unknown
+    /// or not disputed, rather than failing with `TransactionNotFound` or
+    /// `NotDisputed`.
+    pub lenient_resolve: bool,
+
+    /// Forget deposits and withdrawals once this many newer ones have
+    /// been recorded, bounding memory on long running feeds. Disputes of
+    /// a forgotten transaction fail with `TransactionNotFound`, and its ID
+    /// may be reused. Disputed transactions are kept until resolved.
+    pub prune_after: Option<usize>,
+
+    /// Check that each chargeback releases exactly the disputed amount
+    /// from held funds without driving them negative, failing with
+    /// `UnbalancedChargeback` otherwise.
+    pub verify_chargebacks: bool,
+
+    /// Refuse deposits and withdrawals from a client who already has this
+    /// many applied, failing with `RateLimited`. Disputes and their
+    /// outcomes don't count. `None` allows any number.
+    pub max_tx_per_client: Option<u32>,
+
+    /// Refuse withdrawals which would leave available funds below this
+    /// floor, failing with `BelowMinimumBalance`. `None` only refuses
+    /// withdrawals of more than the available funds.
+    pub min_balance: Option<f64>,
+
+    /// Refuse deposits and withdrawals of more than this amount, failing
+    /// with `AmountTooLarge`, to catch mistyped or fraudulent amounts.
+    /// `None` allows any amount.
+    pub max_amount: Option<f64>,
+
+    /// Lock any account whose available funds go negative, or which owes
+    /// a shortfall, blaming the transaction which took it below zero.
+    /// Unlike a chargeback lock this still lets the disputes already open
+    /// be resolved or charged back, so their held funds aren't stuck.
+    pub freeze_on_negative: bool,
+
+    /// Refuse disputes, resolves and chargebacks which carry an amount,
+    /// failing with `Malformed`, rather than ignoring it. An amount there
+    /// usually means the partner's export is broken.
+    pub strict_dispute_amounts: bool,
+
+    /// Accept closes, which pay out and permanently lock an account. They
+    /// fail with `Unauthorized` otherwise, since a partner's feed
+    /// shouldn't be able to close accounts unless the operator allows it.
+    pub allow_close: bool,
+}
+
+/// Custom business rules checked before each transaction is applied, for
+/// rules too specific to the deployment to be a `LedgerOptions` field,
+/// eg. refusing withdrawals over a threshold.
+pub trait TransactionHook: Debug + Send + Sync {
+    /// Called before `transaction` moves any funds, with the client's
+    /// account as it stands. A client without an account is passed a new
+    /// one, which isn't created if this fails. Returning an error, eg.
+    /// `TransactionError::Rejected`, refuses the transaction.
+    fn before_apply(
+        &self,
+        transaction: &Transaction,
+        account: &Account,
+    ) -> Result<(), TransactionError>;
+}
+
+/// What `Ledger::process_with_report` read and applied, for logging and
+/// metrics.
+#[derive(Debug, Default, PartialEq)]
+pub struct ProcessReport {
+    /// Number of transactions read.
+    pub read: usize,
+
+    /// Number of transactions applied. An unbalanced chargeback is still
+    /// applied, so is counted here as well as in `failures`.
+    pub applied: usize,
+
+    /// Number of transactions read of each type, indexed like
+    /// `TransactionType::ALL`. See `count`.
+    pub counts: [usize; TransactionType::ALL.len()],
+
+    /// Transactions which failed to apply, in input order, each paired
+    /// with its row as in `Ledger::process_lenient`.
+    pub failures: Vec<(usize, TransactionError)>,
+}
+
+impl ProcessReport {
+    /// Number of transactions of `tx_type` read.
+    pub fn count(&self, tx_type: TransactionType) -> usize {
+        self.counts[tx_type.code() as usize - 1]
+    }
+}
+
+/// Cloning a ledger is a deep copy, so a clone can be used to try out
+/// transactions without affecting the original.
+#[derive(Debug, Clone)]
 pub struct Ledger {
-    pub transactions: HashMap<u32, Transaction>,
-    pub accounts: HashMap<u16, Account>,
+    pub transactions: Map<u32, Transaction>,
+    /// Accounts by client ID. Ordered so that iteration, and so output,
+    /// is deterministic.
+    pub accounts: BTreeMap<u16, Account>,
+    pub options: LedgerOptions,
+
+    /// Clients allowed to transact. Transactions from any other client
+    /// fail with `UnknownClient` and aren't recorded. `None` allows any
+    /// client, creating their account on first use.
+    pub registered_clients: Option<BTreeSet<u16>>,
+
+    /// IDs of every deposit and withdrawal recorded, including any since
+    /// pruned, see `LedgerOptions::prune_after`. A recorded ID found here
+    /// fails with `DuplicateTransactionID`, which may be a false positive.
+    /// `None` only rejects IDs still held in `transactions`.
+    pub seen_tx_ids: Option<BloomFilter>,
+
+    /// Checked before every transaction, after the built in checks for
+    /// registration. Shared with clones of the ledger.
+    pub hook: Option<Arc<dyn TransactionHook>>,
+
+    /// Next candidate for `next_internal_tx_id`. Counts down from
+    /// `u32::MAX` so internal ids stay clear of partner-supplied ids, and
+    /// is `None` once ID 0 has been handed out.
+    pub internal_tx_id: Option<u32>,
+
+    /// Recorded transaction IDs in the order they were recorded, with the
+    /// sequence number they were recorded at. Only kept when pruning.
+    recorded: VecDeque<(u64, u32)>,
+
+    /// Number of transactions recorded while pruning.
+    recorded_count: u64,
+
+    /// Clients whose accounts changed since they were last taken. Only
+    /// kept once `track_changes` is called.
+    changed: Option<BTreeSet<u16>>,
 }
 
 impl Ledger {
-    pub fn new(transactions: HashMap<u32, Transaction>, accounts: HashMap<u16, Account>) -> Self {
+    pub fn new(transactions: Map<u32, Transaction>, accounts: BTreeMap<u16, Account>) -> Self {
         Ledger {
             transactions,
             accounts,
+            options: LedgerOptions::default(),
+            registered_clients: None,
+            seen_tx_ids: None,
+            hook: None,
+            internal_tx_id: Some(u32::MAX),
+            recorded: VecDeque::new(),
+            recorded_count: 0,
+            changed: None,
+        }
+    }
+
+    /// Notes that `tx_id` was just recorded and, when `prune_after` is set,
+    /// forgets recorded transactions which have been followed by that many
+    /// newer ones. A disputed transaction is requeued as if just recorded,
+    /// since its resolve or chargeback still needs it.
+    pub(crate) fn note_recorded(&mut self, tx_id: u32) {
+        let Some(prune_after) = self.options.prune_after else {
+            return;
+        };
+
+        self.recorded.push_back((self.recorded_count, tx_id));
+        self.recorded_count += 1;
+
+        while let Some(&(sequence, oldest)) = self.recorded.front() {
+            if sequence + prune_after as u64 >= self.recorded_count {
+                break;
+            }
+
+            self.recorded.pop_front();
+
+            if self.transactions.get(&oldest).is_some_and(|tx| tx.disputed) {
+                self.recorded.push_back((self.recorded_count, oldest));
+            } else {
+                self.transactions.remove(&oldest);
+            }
+        }
+    }
+
+    /// Starts tracking which clients' accounts change, for `take_changed`.
+    pub fn track_changes(&mut self) {
+        self.changed.get_or_insert_with(BTreeSet::new);
+    }
+
+    /// Returns the clients, in ascending order, whose accounts changed
+    /// since the last call. Always empty unless changes are tracked.
+    pub fn take_changed(&mut self) -> Vec<u16> {
+        self.changed
+            .as_mut()
+            .map(|changed| core::mem::take(changed).into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn mark_changed(&mut self, client_id: u16) {
+        if let Some(changed) = &mut self.changed {
+            changed.insert(client_id);
         }
     }
+
+    /// Allocates a transaction ID for a transaction generated by the
+    /// ledger itself rather than supplied by a partner.
+    ///
+    /// IDs are handed out from the top of the `u32` range downwards,
+    /// skipping any ID already recorded, so internal transactions can be
+    /// stored and disputed like any other without clashing. Returns `None`
+    /// once there are none left.
+    pub fn next_internal_tx_id(&mut self) -> Option<u32> {
+        while let Some(tx_id) = self.internal_tx_id {
+            self.internal_tx_id = tx_id.checked_sub(1);
+
+            if !self.transactions.contains_key(&tx_id) {
+                return Some(tx_id);
+            }
+        }
+
+        None
+    }
+
+    /// Charges every account holding funds a fee of `rate` times its held
+    /// funds, eg. `0.01` for 1%. Fees are taken from available funds, which
+    /// may go negative, so that held funds still match the open disputes.
+    /// Each fee is recorded as a withdrawal with an internal transaction ID,
+    /// and no more fees are charged once the IDs run out.
+    pub fn charge_held_fee(&mut self, rate: f64) -> Result<(), TransactionError> {
+        let fees: Vec<(u16, f64)> = self
+            .accounts
+            .values()
+            .filter(|account| account.held_funds > 0.0)
+            .map(|account| (account.client_id, rate * account.held_funds))
+            .collect();
+
+        for (client_id, fee) in fees {
+            let tx_id = self.reserve_internal_tx_id()?;
+            self.record_internal(tx_id, TransactionType::Withdrawal, client_id, fee);
+
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                account.available_funds -= fee;
+                account.rebalance(self.options.track_shortfall);
+                if let Some(precision) = self.options.precision {
+                    account.round_funds(precision, self.options.rounding);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the available funds of every account locked by a chargeback
+    /// into the account of `to`, eg. a house account, which is created if
+    /// need be. Each move is recorded as a withdrawal from the locked
+    /// account and a deposit to `to`, under internal transaction IDs, and
+    /// no more accounts are swept once the IDs run out.
+    pub fn sweep_locked(&mut self, to: u16) -> Result<(), TransactionError> {
+        let sweeps: Vec<(u16, f64)> = self
+            .accounts
+            .values()
+            .filter(|account| {
+                account.status == AccountStatus::Locked
+                    && account.client_id != to
+                    && account.available_funds > 0.0
+            })
+            .map(|account| (account.client_id, account.available_funds))
+            .collect();
+
+        for (client_id, funds) in sweeps {
+            let withdrawal = self.reserve_internal_tx_id()?;
+            let deposit = self.reserve_internal_tx_id()?;
+            self.record_internal(withdrawal, TransactionType::Withdrawal, client_id, funds);
+            self.record_internal(deposit, TransactionType::Deposit, to, funds);
+
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                account.available_funds = 0.0;
+                account.rebalance(self.options.track_shortfall);
+            }
+
+            let sweep_account = self.accounts.entry(to).or_insert_with(|| Account::new(to));
+            sweep_account.available_funds += funds;
+            sweep_account.rebalance(self.options.track_shortfall);
+            if let Some(precision) = self.options.precision {
+                sweep_account.round_funds(precision, self.options.rounding);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `next_internal_tx_id`, as an error once there are none left.
+    fn reserve_internal_tx_id(&mut self) -> Result<u32, TransactionError> {
+        self.next_internal_tx_id()
+            .ok_or(TransactionError::OutOfInternalIds)
+    }
+
+    /// Records a deposit or withdrawal of `amount` generated by the ledger
+    /// itself under `tx_id`, so it shows up in the audit like any other.
+    /// The caller moves the funds.
+    fn record_internal(
+        &mut self,
+        tx_id: u32,
+        tx_type: TransactionType,
+        client_id: u16,
+        amount: f64,
+    ) {
+        let settled = match tx_type {
+            TransactionType::Withdrawal => -amount,
+            _ => amount,
+        };
+
+        self.transactions.insert(
+            tx_id,
+            Transaction {
+                tx_type,
+                client_id,
+                tx_id,
+                amount: Some(amount),
+                disputed: false,
+                settled,
+                date: None,
+                comment: None,
+                #[cfg(feature = "std")]
+                metadata: Default::default(),
+            },
+        );
+
+        self.mark_changed(client_id);
+    }
+
+    /// Returns whether the transaction is currently disputed, or `None` if
+    /// the transaction is unknown. Only deposits and withdrawals are recorded.
+    pub fn is_disputed(&self, tx_id: u32) -> Option<bool> {
+        self.transactions.get(&tx_id).map(|tx| tx.disputed)
+    }
+
+    /// Returns the partner's comment on a recorded transaction, if it has
+    /// one. Only deposits and withdrawals are recorded.
+    pub fn comment(&self, tx_id: u32) -> Option<&str> {
+        self.transactions.get(&tx_id)?.comment.as_deref()
+    }
+
+    /// Rough number of bytes taken by the ledger's transactions and
+    /// accounts, counting only their fixed size. Comments, metadata and
+    /// the maps' own overhead aren't counted.
+    pub fn estimated_memory(&self) -> usize {
+        self.transactions.len() * core::mem::size_of::<Transaction>()
+            + self.accounts.len() * core::mem::size_of::<Account>()
+    }
+
+    /// Returns a copy of every account in ascending client ID order, the
+    /// order all of the outputs are written in.
+    pub fn export_accounts(&self) -> Vec<Account> {
+        self.accounts.values().cloned().collect()
+    }
+
+    /// Returns the IDs of clients, in ascending order, whose held funds
+    /// don't match the summed amounts of their disputed transactions.
+    /// Any result indicates the account and transaction views have drifted.
+    pub fn reconcile_holds(&self) -> Vec<u16> {
+        let mut disputed: Map<u16, f64> = Map::new();
+
+        for tx in self.transactions.values().filter(|tx| tx.disputed) {
+            *disputed.entry(tx.client_id).or_default() += tx.amount.unwrap_or_default();
+        }
+
+        // Accounts are kept in client order, so the result is too.
+        self.accounts
+            .values()
+            .filter(|account| {
+                let expected = disputed
+                    .get(&account.client_id)
+                    .copied()
+                    .unwrap_or_default();
+                libm::fabs(account.held_funds - expected) > RECONCILE_EPSILON
+            })
+            .map(|account| account.client_id)
+            .collect()
+    }
+
+    /// Returns the IDs of clients, in ascending order, whose balances don't
+    /// match those recomputed from the recorded transactions: available
+    /// funds from what each transaction settled less any disputed deposits,
+    /// and held funds from the disputed amounts. Clients with recorded
+    /// transactions but no account are included.
+    ///
+    /// Pruned transactions can't be recomputed, so the result is only
+    /// meaningful without `prune_after`. Rounding to `precision` may also
+    /// be reported when amounts have more decimal places than it keeps.
+    pub fn validate_consistency(&self) -> Vec<u16> {
+        let mut expected: BTreeMap<u16, (f64, f64)> = self
+            .accounts
+            .keys()
+            .map(|&client_id| (client_id, (0.0, 0.0)))
+            .collect();
+
+        for tx in self.transactions.values() {
+            let (available, held) = expected.entry(tx.client_id).or_default();
+            *available += tx.settled;
+
+            if tx.disputed {
+                let amount = tx.amount.unwrap_or_default();
+                *held += amount;
+                if tx.tx_type == TransactionType::Deposit {
+                    *available -= amount;
+                }
+            }
+        }
+
+        expected
+            .into_iter()
+            .filter(
+                |(client_id, (available, held))| match self.accounts.get(client_id) {
+                    Some(account) => {
+                        libm::fabs(account.available_funds - account.shortfall - available)
+                            > RECONCILE_EPSILON
+                            || libm::fabs(account.held_funds - held) > RECONCILE_EPSILON
+                    }
+                    None => true,
+                },
+            )
+            .map(|(client_id, _)| client_id)
+            .collect()
+    }
+
+    /// Folds a ledger processed independently, eg. from a disjoint shard
+    /// of clients, into this one.
+    ///
+    /// Accounts present in both ledgers have their funds summed and are
+    /// locked if either copy is locked. Transaction IDs must not overlap,
+    /// if any do the merge fails with `DuplicateTransactionID` and this
+    /// ledger is left untouched.
+    pub fn merge(&mut self, other: Ledger) -> Result<(), TransactionError> {
+        if other
+            .transactions
+            .keys()
+            .any(|tx_id| self.transactions.contains_key(tx_id))
+        {
+            return Err(TransactionError::DuplicateTransactionID);
+        }
+
+        if let Some(seen) = &mut self.seen_tx_ids {
+            for &tx_id in other.transactions.keys() {
+                seen.insert(tx_id);
+            }
+        }
+
+        self.transactions.extend(other.transactions);
+
+        for (client_id, other_account) in other.accounts {
+            self.mark_changed(client_id);
+
+            let account = self
+                .accounts
+                .entry(client_id)
+                .or_insert_with(|| Account::new(client_id));
+
+            account.available_funds += other_account.available_funds;
+            account.held_funds += other_account.held_funds;
+            account.shortfall += other_account.shortfall;
+            account.open_disputes += other_account.open_disputes;
+            account.transaction_count += other_account.transaction_count;
+
+            if !account.is_locked() {
+                account.status = other_account.status;
+                account.locked_by_tx = other_account.locked_by_tx;
+            }
+        }
+
+        self.internal_tx_id = self.internal_tx_id.min(other.internal_tx_id);
+
+        Ok(())
+    }
+
+    /// Returns the IDs of clients whose accounts differ between this ledger
+    /// and `other`, in ascending order. Clients present in only one of the
+    /// ledgers are included. Funds within `epsilon` of each other are
+    /// considered equal since `f64` arithmetic drifts.
+    pub fn diff(&self, other: &Ledger, epsilon: f64) -> Vec<u16> {
+        let mut client_ids: Vec<u16> = self
+            .accounts
+            .keys()
+            .chain(
+                other
+                    .accounts
+                    .keys()
+                    .filter(|id| !self.accounts.contains_key(id)),
+            )
+            .filter(|id| match (self.accounts.get(id), other.accounts.get(id)) {
+                (Some(left), Some(right)) => !accounts_match(left, right, epsilon),
+                _ => true,
+            })
+            .copied()
+            .collect();
+
+        client_ids.sort_unstable();
+        client_ids
+    }
+
+    /// Appends each transaction to the ledger in order, returning the
+    /// errors of any which failed. Failed transactions don't stop the
+    /// remaining transactions from being applied.
+    pub fn apply_all<I: IntoIterator<Item = Transaction>>(
+        &mut self,
+        transactions: I,
+    ) -> Vec<TransactionError> {
+        transactions
+            .into_iter()
+            .filter_map(|transaction| transaction.append_to(self).err())
+            .collect()
+    }
+
+    /// Applies each transaction from `stream` as it arrives, yielding the
+    /// result of each, as from `Transaction::append_to`, in order. The
+    /// ledger is borrowed until the returned stream is dropped.
+    #[cfg(feature = "async")]
+    pub fn process_stream<'a, S>(
+        &'a mut self,
+        stream: S,
+    ) -> impl futures::Stream<Item = Result<crate::account::AccountSnapshot, TransactionError>> + 'a
+    where
+        S: futures::Stream<Item = Transaction> + 'a,
+    {
+        use futures::StreamExt;
+
+        stream.map(move |transaction| transaction.append_to(self))
+    }
+
+    /// Like `process`, but collects the errors of transactions which fail
+    /// to apply, in input order. Each error is paired with the 1-based row
+    /// of the transaction, counting the header as row 1, so the first
+    /// transaction is row 2.
+    #[cfg(feature = "std")]
+    pub fn process_lenient<R: Read>(
+        &mut self,
+        reader: R,
+    ) -> Result<Vec<(usize, TransactionError)>, csv::Error> {
+        let mut errors = Vec::new();
+
+        for (index, transaction) in
+            TransactionReader::new(reader, AmountFormat::default())?.enumerate()
+        {
+            if let Err(error) = transaction?.append_to(self) {
+                errors.push((index + 2, error));
+            }
+        }
+
+        Ok(errors)
+    }
+
+    /// Like `process_lenient`, but also counts the transactions read, in
+    /// total and of each type, and those applied.
+    #[cfg(feature = "std")]
+    pub fn process_with_report<R: Read>(&mut self, reader: R) -> Result<ProcessReport, csv::Error> {
+        let mut report = ProcessReport::default();
+
+        for (index, transaction) in
+            TransactionReader::new(reader, AmountFormat::default())?.enumerate()
+        {
+            let transaction = transaction?;
+            report.read += 1;
+            report.counts[transaction.tx_type.code() as usize - 1] += 1;
+
+            let result = transaction.append_to(self);
+
+            if matches!(
+                result,
+                Ok(_) | Err(TransactionError::UnbalancedChargeback { .. })
+            ) {
+                report.applied += 1;
+            }
+
+            if let Err(error) = result {
+                report.failures.push((index + 2, error));
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Reads transactions as CSV from `reader` and appends each to the
+    /// ledger. Transactions which fail to apply are skipped, only rows
+    /// which can't be parsed stop processing.
+    #[cfg(feature = "std")]
+    pub fn process<R: Read>(&mut self, reader: R) -> Result<(), csv::Error> {
+        for transaction in TransactionReader::new(reader, AmountFormat::default())? {
+            // We don't care about the errors here.
+            let _ = transaction?.append_to(self);
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,27 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod account;
+pub mod bloom;
+pub mod ledger;
+#[cfg(feature = "std")]
+pub mod output;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod transaction;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Map used for the ledger's transactions. Without `std`
+/// there is no `HashMap`, so an ordered map from `alloc` stands in.
+#[cfg(feature = "std")]
+pub type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub type Map<K, V> = alloc::collections::BTreeMap<K, V>;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::btree_map::Entry;
+#[cfg(feature = "std")]
+pub(crate) use std::collections::hash_map::Entry;
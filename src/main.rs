@@ -1,81 +1,5863 @@
-use clap::Parser;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    error::Error,
+    fs::File,
+    io::{Read, Stdout, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-mod account;
-mod ledger;
-mod transaction;
+use clap::{CommandFactory, Parser};
+use serde::{Deserialize, Serialize};
+
+use toy_ledger::{
+    account::{format_funds, Account, AccountStatus, Rounding},
+    bloom::BloomFilter,
+    ledger::{Ledger, LedgerOptions},
+    output::{
+        self, AccountChange, AccountColumn, AccountColumns, CashFlow, PartnerAccount,
+        RejectedTransaction, TypeCount, VerboseAccount,
+    },
+    transaction::{
+        csv_reader, json_transactions, AmountFormat, Transaction, TransactionError,
+        TransactionReader, TransactionType, SCHEMA_COLUMNS,
+    },
+};
+
+use crate::config::Config;
+
+mod config;
+mod explain;
+mod follow;
+mod generate;
+#[cfg(feature = "serve")]
+mod serve;
+mod state;
+#[cfg(feature = "watch")]
+mod watch;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Csv,
+    Json,
+    /// Aligned columns for reading in a terminal, not for parsing.
+    Table,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum InputFormat {
+    Csv,
+    /// One JSON object per transaction, eg. one per line.
+    Json,
+}
+
+/// What to do when a check of the input fails, eg. an amount's `f64`
+/// differs from the decimal written.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum CheckAction {
+    Warn,
+    Error,
+}
+
+/// How rejected transactions are printed on stderr.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ErrorFormat {
+    Text,
+    /// One JSON object per line, eg. for a log pipeline.
+    Json,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+enum QuoteStyle {
+    Necessary,
+    Always,
+    Never,
+}
+
+impl From<QuoteStyle> for csv::QuoteStyle {
+    fn from(style: QuoteStyle) -> Self {
+        match style {
+            QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            QuoteStyle::Always => csv::QuoteStyle::Always,
+            QuoteStyle::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum RoundingMode {
+    /// Halves round away from zero, eg. `0.125` to `0.13`.
+    HalfAwayFromZero,
+    /// Halves round to the even digit, eg. `0.125` to `0.12`.
+    HalfEven,
+    /// Extra places are dropped, eg. `0.129` to `0.12`.
+    TowardZero,
+}
+
+impl From<RoundingMode> for Rounding {
+    fn from(mode: RoundingMode) -> Self {
+        match mode {
+            RoundingMode::HalfAwayFromZero => Rounding::HalfAwayFromZero,
+            RoundingMode::HalfEven => Rounding::HalfEven,
+            RoundingMode::TowardZero => Rounding::TowardZero,
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Write a random transactions CSV to stdout, eg. for benchmarks.
+    Generate {
+        /// Number of transactions to generate.
+        #[clap(long)]
+        count: usize,
+
+        /// Number of distinct clients.
+        #[clap(long, default_value = "100", value_parser = clap::value_parser!(u16).range(1..))]
+        clients: u16,
+
+        /// Seed for the random generator, the same seed always
+        /// produces the same output.
+        #[clap(long, default_value = "0")]
+        seed: u64,
+    },
+
+    /// Print a completion script for the given shell to stdout.
+    Completions {
+        #[clap(arg_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Replay a transactions CSV into fresh accounts and check they match
+    /// an accounts snapshot, eg. one written by `--daily-snapshots`,
+    /// printing each client which differs. Ledger options are read from
+    /// the config file.
+    VerifySnapshot {
+        /// Accounts the transactions should produce.
+        snapshot: PathBuf,
+
+        /// Transactions to replay.
+        transactions: PathBuf,
+    },
+
+    /// Serve an in-memory ledger over HTTP: `POST /transactions` applies a
+    /// CSV or JSON body and `GET /accounts` returns the accounts as JSON.
+    /// Ledger options are read from the config file.
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Address to listen on.
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
-    #[clap(index = 1)]
-    csv_filename: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Transactions CSV files, processed in order into a single ledger.
+    /// Read from stdin if neither these nor `LEDGER_INPUT` are set.
+    #[clap(index = 1, env = "LEDGER_INPUT")]
+    csv_filenames: Vec<PathBuf>,
+
+    /// Format of the transaction inputs. JSON objects may carry extra
+    /// fields, which are kept for `--audit-json`.
+    #[clap(long, arg_enum, default_value = "csv")]
+    input_format: InputFormat,
+
+    /// Config file providing defaults for the flags below.
+    /// Defaults to `ledger.toml` in the current directory if present.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Clamp available funds at zero when a dispute would drive them
+    /// negative, tracking the deficit as a shortfall instead.
+    #[clap(long, overrides_with = "no-track-shortfall")]
+    track_shortfall: bool,
+
+    /// Undo `--track-shortfall`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "track-shortfall")]
+    no_track_shortfall: bool,
+
+    /// Refuse chargebacks of deposits whose funds have already been spent.
+    #[clap(long, overrides_with = "no-reject-uncovered-chargeback")]
+    reject_uncovered_chargeback: bool,
+
+    /// Undo `--reject-uncovered-chargeback`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "reject-uncovered-chargeback")]
+    no_reject_uncovered_chargeback: bool,
+
+    /// Accept deposits into accounts locked by a chargeback.
+    #[clap(long, overrides_with = "no-allow-deposit-when-locked")]
+    allow_deposit_when_locked: bool,
+
+    /// Undo `--allow-deposit-when-locked`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "allow-deposit-when-locked")]
+    no_allow_deposit_when_locked: bool,
+
+    /// Refuse disputes from clients who already have this many open.
+    #[clap(long, value_name = "n")]
+    max_open_disputes: Option<u32>,
+
+    /// Ignore resolves and chargebacks of transactions which are unknown
+    /// or not disputed.
+    #[clap(long, overrides_with = "no-lenient-resolve")]
+    lenient_resolve: bool,
+
+    /// Undo `--lenient-resolve`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "lenient-resolve")]
+    no_lenient_resolve: bool,
+
+    /// Forget deposits and withdrawals once `n` newer ones have been
+    /// recorded, unless disputed. Later disputes of them are rejected.
+    #[clap(long, value_name = "n")]
+    prune_after: Option<usize>,
+
+    /// Reject reused deposit and withdrawal IDs, even once pruned, using a
+    /// bloom filter sized for `n` IDs. Takes far less memory than keeping
+    /// every transaction, but some new IDs are wrongly rejected as
+    /// duplicates, see `--bloom-false-positive-rate`.
+    #[clap(long, value_name = "n")]
+    bloom_capacity: Option<usize>,
+
+    /// Fraction of new IDs the bloom filter may wrongly reject, eg. `0.001`.
+    #[clap(long, value_name = "rate", default_value = "0.001", requires = "bloom-capacity", value_parser = parse_rate)]
+    bloom_false_positive_rate: f64,
+
+    /// Check that chargebacks release exactly the disputed amount from
+    /// held funds, without driving them negative.
+    #[clap(long, overrides_with = "no-verify-chargebacks")]
+    verify_chargebacks: bool,
+
+    /// Undo `--verify-chargebacks`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "verify-chargebacks")]
+    no_verify_chargebacks: bool,
+
+    /// Refuse deposits and withdrawals from clients who already have `n`
+    /// applied. Disputes don't count.
+    #[clap(long, value_name = "n")]
+    max_tx_per_client: Option<u32>,
+
+    /// Refuse withdrawals which would leave less than this amount, eg.
+    /// `10`, available. Written in the same scale as input amounts.
+    #[clap(long, value_name = "amount", value_parser = parse_min_balance)]
+    min_balance: Option<f64>,
+
+    /// Refuse deposits and withdrawals of more than this amount. Written
+    /// in the same scale as input amounts.
+    #[clap(long, value_name = "amount", value_parser = parse_max_amount)]
+    max_amount: Option<f64>,
+
+    /// Lock any account whose available funds go negative, eg. after a
+    /// deposit the client already spent is disputed. Disputes already open
+    /// can still be resolved or charged back.
+    #[clap(long, overrides_with = "no-freeze-on-negative")]
+    freeze_on_negative: bool,
+
+    /// Undo `--freeze-on-negative`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "freeze-on-negative")]
+    no_freeze_on_negative: bool,
+
+    /// Reject disputes, resolves and chargebacks which have an amount,
+    /// rather than ignoring it.
+    #[clap(long, overrides_with = "no-strict-dispute-amounts")]
+    strict_dispute_amounts: bool,
+
+    /// Undo `--strict-dispute-amounts`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "strict-dispute-amounts")]
+    no_strict_dispute_amounts: bool,
+
+    /// Accept close transactions, which pay out the client's available
+    /// funds and permanently lock their account.
+    #[clap(long, overrides_with = "no-allow-close")]
+    allow_close: bool,
+
+    /// Undo `--allow-close`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "allow-close")]
+    no_allow_close: bool,
+
+    /// Reject transactions from clients not listed in this file, which
+    /// holds one client ID per line, instead of creating their accounts.
+    #[clap(long, value_name = "clients.txt")]
+    require_registered_clients: Option<PathBuf>,
+
+    /// Round funds to this many decimal places after each dispute,
+    /// resolve and chargeback.
+    #[clap(long, value_name = "places")]
+    precision: Option<u32>,
+
+    /// How funds are rounded to `--precision` places
+    /// [default: half-away-from-zero]
+    #[clap(long, arg_enum, value_name = "mode")]
+    rounding: Option<RoundingMode>,
+
+    /// Format used to write the accounts to stdout [default: csv]
+    #[clap(long, arg_enum)]
+    format: Option<OutputFormat>,
+
+    /// When to quote fields in CSV output.
+    #[clap(long, arg_enum, default_value = "necessary")]
+    quote_style: QuoteStyle,
+
+    /// Append `#` comment lines totalling the accounts after CSV output.
+    #[clap(long)]
+    summary_footer: bool,
+
+    /// Only write the accounts of these clients, eg. `--client 1,7`.
+    /// Every transaction is still applied.
+    #[clap(long = "client", value_name = "id", use_value_delimiter = true)]
+    clients: Vec<u16>,
+
+    /// Only write the `n` accounts with the highest total funds, highest
+    /// first. Ties are ordered by client ID.
+    #[clap(long, value_name = "n")]
+    top: Option<usize>,
+
+    /// Indent JSON output for readability.
+    #[clap(long, overrides_with = "no-pretty")]
+    pretty: bool,
+
+    /// Undo `--pretty`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "pretty")]
+    no_pretty: bool,
+
+    /// Annotate each account with details derived from its transactions,
+    /// such as the number of open disputes.
+    #[clap(long)]
+    verbose: bool,
+
+    /// Write each client's total deposited, total withdrawn and net flow
+    /// next to their account's total, rather than the accounts.
+    #[clap(long, conflicts_with = "verbose")]
+    cashflow: bool,
+
+    /// Write only these account columns, in this order, eg.
+    /// `client,total`.
+    #[clap(
+        long,
+        value_name = "columns",
+        use_value_delimiter = true,
+        value_parser = AccountColumn::from_str,
+        conflicts_with_all = &["verbose", "cashflow"]
+    )]
+    output_columns: Option<Vec<AccountColumn>>,
+
+    /// Stop after the first `n` transactions and emit the accounts so far.
+    #[clap(long, value_name = "n")]
+    limit: Option<usize>,
+
+    /// Skip the first `n` transactions of the input, eg. with
+    /// `--resume-from` to carry on from the state saved after them.
+    /// Requires a single input file.
+    #[clap(long, value_name = "n", default_value = "0", conflicts_with = "follow")]
+    skip_rows: usize,
+
+    /// Start from the ledger saved in this file by `--save-state` rather
+    /// than an empty one. Disputes of the saved transactions and reuses of
+    /// their IDs are handled as if they had been processed in this run.
+    #[clap(long, value_name = "path", conflicts_with = "follow")]
+    resume_from: Option<PathBuf>,
+
+    /// Save the ledger's accounts and recorded transactions to this file as
+    /// JSON once processing ends, including when it's interrupted, for
+    /// `--resume-from`.
+    #[clap(long, value_name = "path", conflicts_with = "follow")]
+    save_state: Option<PathBuf>,
+
+    /// Abort once the ledger's estimated memory use passes this many
+    /// megabytes, checked every 1024 transactions. The estimate counts
+    /// each transaction and account at its fixed size.
+    #[clap(long, value_name = "mb")]
+    max_memory: Option<usize>,
+
+    /// Fail if the input contains no transactions, which may mean the
+    /// upstream export failed.
+    #[clap(long)]
+    require_rows: bool,
+
+    /// Fail, without writing the accounts, if any client's held funds are
+    /// negative after processing, which would mean a bug in the dispute
+    /// arithmetic.
+    #[clap(long)]
+    assert_no_negative_held: bool,
+
+    /// Write the number of transactions of each type instead of the
+    /// accounts. Only the `type` column is read and nothing is applied,
+    /// so this is much faster on large files. CSV input only.
+    #[clap(long)]
+    count_only: bool,
+
+    /// Exit with an error if any account's held funds don't match its
+    /// disputed transactions.
+    #[clap(long)]
+    reconcile: bool,
+
+    /// Write the accounts as of the end of each date in the input's
+    /// `date` column to `<dir>/<date>.csv`.
+    #[clap(long, value_name = "dir")]
+    daily_snapshots: Option<PathBuf>,
+
+    /// Fail if a client appears in more than one input file, which
+    /// usually means overlapping shards are being processed twice.
+    #[clap(long)]
+    deny_duplicate_clients_across_files: bool,
+
+    /// Tag the clients of each input file with a partner, given once per
+    /// file in the same order, eg. `a.csv b.csv --partner-id acme
+    /// --partner-id globex`. Accounts are written with the partner of the
+    /// file which first introduced the client in a `partner` column, and a
+    /// client also found in another partner's file is warned about.
+    #[clap(
+        long,
+        value_name = "string",
+        multiple_occurrences = true,
+        conflicts_with_all = &["verbose", "cashflow", "output-columns", "follow"]
+    )]
+    partner_id: Vec<String>,
+
+    /// Strip this thousands separator from amounts before parsing them,
+    /// eg. `,` for `"1,000.50"`.
+    #[clap(long, value_name = "char", value_parser = parse_thousands_separator)]
+    thousands_separator: Option<char>,
+
+    /// Read amounts with this character separating the fraction rather
+    /// than `.`, eg. `,` for `100,50`. Can't be the `--delimiter`.
+    #[clap(long, value_name = "char", value_parser = parse_decimal_separator)]
+    decimal_separator: Option<char>,
+
+    /// Character separating the fields of CSV input, eg. `;` [default: ,]
+    #[clap(long, value_name = "char", value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+
+    /// Number of decimal places input amounts are shifted by, eg. `2` for
+    /// amounts in integer cents. Output funds are shifted back
+    /// [default: 0]
+    #[clap(long, value_name = "n", value_parser = clap::value_parser!(u32).range(..=MAX_AMOUNT_SCALE as i64))]
+    amount_scale: Option<u32>,
+
+    /// Read the `type` column as legacy numeric codes: 1 deposit,
+    /// 2 withdrawal, 3 dispute, 4 resolve, 5 chargeback and 6 close.
+    #[clap(long)]
+    numeric_types: bool,
+
+    /// Fail before processing a CSV input unless its header has exactly
+    /// the columns of this schema version: 1 for `type,client,tx,amount`
+    /// and 2 for those plus `date,comment`. Also fails if the file starts
+    /// with a `#schema=<version>` line naming another version.
+    #[clap(long, value_name = "version", value_parser = clap::value_parser!(u32).range(1..=SCHEMA_COLUMNS.len() as i64))]
+    expect_schema: Option<u32>,
+
+    /// Warn on stderr, or fail, when a CSV amount can't be stored exactly
+    /// as written, eg. `0.1`. Warns if no action is given.
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "action",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "warn"
+    )]
+    detect_precision_loss: Option<CheckAction>,
+
+    /// Report on stderr each deposit or withdrawal in CSV input whose row
+    /// reads differently once parsed and written back out, eg. an amount
+    /// of `1.50` written back as `1.5`.
+    #[clap(long)]
+    round_trip_check: bool,
+
+    /// Keep the input file open and process rows as they're appended,
+    /// writing the accounts whenever new rows have been applied. Rows
+    /// which can't be parsed are skipped, and reported with
+    /// `--log-rejected`.
+    #[clap(long, alias = "tail")]
+    follow: bool,
+
+    /// With `--follow`, write only the accounts changed since the last
+    /// write, as CSV rows stamped with `timestamp_ms`, rather than every
+    /// account. The header row is written once.
+    #[clap(long)]
+    changes_only: bool,
+
+    /// How often, in milliseconds, to check a followed file for new rows.
+    #[clap(long, value_name = "ms", default_value = "1000")]
+    follow_interval: u64,
+
+    /// Process each `.csv` file dropped into this directory, writing the
+    /// accounts, and any `--save-state`, after each. Files already
+    /// processed, as listed in the directory's `.processed` file, are
+    /// skipped, so a stopped watch can be carried on with `--resume-from`
+    /// its saved state.
+    #[cfg(feature = "watch")]
+    #[clap(
+        long,
+        value_name = "dir",
+        conflicts_with_all = &["csv-filenames", "follow", "skip-rows", "count-only", "partner-id"]
+    )]
+    watch: Option<PathBuf>,
+
+    /// Write the accounts to two files rather than stdout, unlocked ones to
+    /// the first path and locked ones to the second, each with its own
+    /// header. An omitted or empty path drops those accounts, eg.
+    /// `--split-output active.csv` or `--split-output "" locked.csv`.
+    #[clap(long, value_name = "path", min_values = 1, max_values = 2)]
+    split_output: Option<Vec<PathBuf>>,
+
+    /// Print each rejected transaction and why to stderr.
+    #[clap(long, overrides_with = "no-log-rejected")]
+    log_rejected: bool,
+
+    /// Undo `--log-rejected`, eg. when it's set in the config file.
+    #[clap(long, overrides_with = "log-rejected")]
+    no_log_rejected: bool,
+
+    /// How to print rejected transactions. `json` writes one object per
+    /// line with the error's name, and implies `--log-rejected`
+    /// [default: text]
+    #[clap(long, arg_enum, value_name = "format")]
+    error_format: Option<ErrorFormat>,
+
+    /// Only apply deposits, so each account's funds are the client's gross
+    /// deposits. Every other transaction is skipped.
+    #[clap(long, conflicts_with = "withdrawal-only")]
+    deposit_only: bool,
+
+    /// Only apply withdrawals, so each account's funds are the client's
+    /// gross withdrawals. Withdrawals are added to the funds rather than
+    /// taken from them, so none are refused for insufficient funds.
+    /// Every other transaction is skipped.
+    #[clap(long)]
+    withdrawal_only: bool,
+
+    /// Print to stderr how the transaction with this ID, and any dispute,
+    /// resolve or chargeback of it, changed the client's account.
+    #[clap(long, value_name = "tx_id")]
+    explain: Option<u32>,
+
+    /// After processing, reverse the deposits and withdrawals in this
+    /// file, eg. one of the inputs which was applied by mistake.
+    #[clap(long, value_name = "path")]
+    undo: Option<PathBuf>,
+
+    /// After processing, move the available funds of every account locked
+    /// by a chargeback into this client's account, eg. a house account.
+    #[clap(long, value_name = "client")]
+    sweep_locked_to: Option<u16>,
+
+    /// After processing, charge each account this fraction of its held
+    /// funds, eg. `0.01`, taken from its available funds.
+    #[clap(long, value_name = "rate", value_parser = parse_rate)]
+    held_fee: Option<f64>,
+
+    /// Also write the recorded deposits and withdrawals, with any extra
+    /// fields from JSON input, to this file as a JSON array.
+    #[clap(long, value_name = "path")]
+    audit_json: Option<PathBuf>,
+
+    /// Exit with an error listing the clients whose accounts differ from
+    /// this expected output CSV.
+    #[clap(long, value_name = "expected.csv")]
+    compare: Option<PathBuf>,
+
+    /// What to do when an accounts file read in, such as the `--compare`
+    /// output, has a `total` other than its available plus held funds.
+    /// A total below that of an account with no available funds is read
+    /// as its shortfall, see `--track-shortfall`. Other totals read in are
+    /// never used, they're always recomputed.
+    #[clap(long, arg_enum, value_name = "action", default_value = "warn")]
+    total_mismatch: CheckAction,
+
+    /// Also write the accounts and transactions to a SQLite database.
+    #[cfg(feature = "sqlite")]
+    #[clap(long, value_name = "path")]
+    sqlite: Option<PathBuf>,
 }
 
-fn main() {
-    let args = Args::parse();
-    let filename = args.csv_filename;
-    let file = std::fs::File::open(filename).expect("Failed to read input file.");
-
-    let mut rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All) // example file contains space padding
-        .flexible(true)
-        .from_reader(file);
-
-    let mut ledger = crate::ledger::Ledger::new(
-        std::collections::HashMap::new(),
-        std::collections::HashMap::new(),
+fn parse_thousands_separator(s: &str) -> Result<char, String> {
+    match s.parse::<char>() {
+        Ok('.') => Err("'.' is the decimal separator".to_string()),
+        Ok(separator) => Ok(separator),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_decimal_separator(s: &str) -> Result<char, String> {
+    match s.parse::<char>() {
+        Ok(separator) if separator.is_ascii_digit() || separator == '-' => {
+            Err(format!("'{}' is part of the amount", separator))
+        }
+        Ok(separator) => Ok(separator),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Largest `--amount-scale`, beyond which amounts can't be shifted
+/// without losing digits.
+const MAX_AMOUNT_SCALE: u32 = 18;
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s.parse::<char>() {
+        Ok(delimiter) if delimiter.is_ascii() => Ok(delimiter as u8),
+        Ok(_) => Err("delimiter must be an ASCII character".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_min_balance(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(floor) if floor.is_finite() && floor >= 0.0 => Ok(floor),
+        Ok(_) => Err("minimum balance must be zero or more".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_max_amount(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(max) if max.is_finite() && max > 0.0 => Ok(max),
+        Ok(_) => Err("maximum amount must be more than zero".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        Ok(_) => Err("rate must be between 0 and 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl Args {
+    /// Fills in options not given on the command line from the config.
+    /// Flags given on the command line always take precedence, and a
+    /// flag set in the config is turned off by its `--no-` form.
+    fn apply_config(&mut self, config: Config) {
+        self.format = self.format.or(config.format);
+        self.pretty = !self.no_pretty && (self.pretty || config.pretty);
+        self.track_shortfall =
+            !self.no_track_shortfall && (self.track_shortfall || config.track_shortfall);
+        self.reject_uncovered_chargeback = !self.no_reject_uncovered_chargeback
+            && (self.reject_uncovered_chargeback || config.reject_uncovered_chargeback);
+        self.precision = self.precision.or(config.precision);
+        self.rounding = self.rounding.or(config.rounding);
+        self.delimiter = self
+            .delimiter
+            .or(config.delimiter.map(|delimiter| delimiter as u8));
+        self.amount_scale = self.amount_scale.or(config.amount_scale);
+        self.log_rejected = !self.no_log_rejected && (self.log_rejected || config.log_rejected);
+        self.error_format = self.error_format.or(config.error_format);
+        self.allow_deposit_when_locked = !self.no_allow_deposit_when_locked
+            && (self.allow_deposit_when_locked || config.allow_deposit_when_locked);
+        self.max_open_disputes = self.max_open_disputes.or(config.max_open_disputes);
+        self.lenient_resolve =
+            !self.no_lenient_resolve && (self.lenient_resolve || config.lenient_resolve);
+        self.prune_after = self.prune_after.or(config.prune_after);
+        self.verify_chargebacks =
+            !self.no_verify_chargebacks && (self.verify_chargebacks || config.verify_chargebacks);
+        self.max_tx_per_client = self.max_tx_per_client.or(config.max_tx_per_client);
+        self.min_balance = self.min_balance.or(config.min_balance);
+        self.max_amount = self.max_amount.or(config.max_amount);
+        self.freeze_on_negative =
+            !self.no_freeze_on_negative && (self.freeze_on_negative || config.freeze_on_negative);
+        self.strict_dispute_amounts = !self.no_strict_dispute_amounts
+            && (self.strict_dispute_amounts || config.strict_dispute_amounts);
+        self.allow_close = !self.no_allow_close && (self.allow_close || config.allow_close);
+    }
+
+    fn ledger_options(&self) -> LedgerOptions {
+        LedgerOptions {
+            track_shortfall: self.track_shortfall,
+            reject_uncovered_chargeback: self.reject_uncovered_chargeback,
+            precision: self.precision,
+            rounding: self.rounding.map_or(Rounding::default(), Into::into),
+            allow_deposit_when_locked: self.allow_deposit_when_locked,
+            max_open_disputes: self.max_open_disputes,
+            lenient_resolve: self.lenient_resolve,
+            prune_after: self.prune_after,
+            verify_chargebacks: self.verify_chargebacks,
+            max_tx_per_client: self.max_tx_per_client,
+            min_balance: self
+                .min_balance
+                .map(|floor| self.amount_format().unscale(floor)),
+            max_amount: self.max_amount.map(|max| self.amount_format().unscale(max)),
+            freeze_on_negative: self.freeze_on_negative,
+            strict_dispute_amounts: self.strict_dispute_amounts,
+            allow_close: self.allow_close,
+        }
+    }
+
+    /// Builds the bloom filter of seen IDs given by `--bloom-capacity`, if any.
+    fn seen_tx_ids(&self) -> Option<BloomFilter> {
+        self.bloom_capacity
+            .map(|capacity| BloomFilter::new(capacity, self.bloom_false_positive_rate))
+    }
+
+    /// Reads the clients given by `--require-registered-clients`, if any.
+    fn registered_clients(&self) -> Option<BTreeSet<u16>> {
+        let path = self.require_registered_clients.as_ref()?;
+        let file = File::open(path).expect("Failed to read registered clients file.");
+
+        Some(read_client_ids(file).expect("Failed to parse registered clients file."))
+    }
+
+    /// Whether the client's account should be written.
+    fn selects(&self, client_id: u16) -> bool {
+        self.clients.is_empty() || self.clients.contains(&client_id)
+    }
+
+    fn transaction_reader<R: Read>(
+        &self,
+        reader: R,
+    ) -> Result<TransactionReader<R>, Box<dyn Error>> {
+        let transactions =
+            TransactionReader::with_delimiter(reader, self.amount_format(), self.delimiter())?
+                .numeric_types(self.numeric_types);
+
+        if let Some(version) = self.expect_schema {
+            transactions.check_schema(version)?;
+        }
+
+        Ok(transactions)
+    }
+
+    /// Reads transactions from `reader` in the `--input-format`, each with
+    /// where it was read from.
+    fn transactions<'a, R: Read + 'a>(
+        &self,
+        reader: R,
+    ) -> Result<Transactions<'a>, Box<dyn Error>> {
+        Ok(match self.input_format {
+            InputFormat::Csv => {
+                let action = self.detect_precision_loss;
+                let mut transactions = self
+                    .transaction_reader(reader)?
+                    .detect_precision_loss(action.is_some())
+                    .check_round_trip(self.round_trip_check);
+                // The header is row 1.
+                let mut row = 1;
+
+                Box::new(std::iter::from_fn(move || {
+                    let transaction = transactions.next()?;
+                    row += 1;
+                    let position = InputPosition {
+                        row,
+                        byte: transactions.byte_offset(),
+                    };
+
+                    match (transactions.precision_loss(), action) {
+                        (Some(loss), Some(CheckAction::Error)) => {
+                            return Some(Err(loss.to_string().into()));
+                        }
+                        (Some(loss), _) => eprintln!("{}", loss),
+                        (None, _) => {}
+                    }
+
+                    for mismatch in transactions.round_trip_mismatches() {
+                        eprintln!("{}", mismatch);
+                    }
+
+                    Some(
+                        transaction
+                            .map(|transaction| (transaction, position))
+                            .map_err(Into::into),
+                    )
+                }))
+            }
+            InputFormat::Json => Box::new(
+                json_transactions(reader, self.amount_format())
+                    .zip(1..)
+                    .map(|(tx, row)| {
+                        let position = InputPosition { row, byte: None };
+                        tx.map(|tx| (tx, position)).map_err(Into::into)
+                    }),
+            ),
+        })
+    }
+
+    fn delimiter(&self) -> u8 {
+        self.delimiter.unwrap_or(b',')
+    }
+
+    fn amount_format(&self) -> AmountFormat {
+        AmountFormat {
+            thousands_separator: self.thousands_separator,
+            decimal_separator: self.decimal_separator,
+            scale: self.amount_scale.unwrap_or(0),
+        }
+    }
+
+    /// Checks that the separators given can't be mistaken for one
+    /// another, eg. a `,` decimal separator in a comma delimited file.
+    fn check_separators(&self) -> Result<(), String> {
+        let Some(decimal) = self.decimal_separator else {
+            return Ok(());
+        };
+
+        if decimal == self.delimiter() as char {
+            return Err(format!(
+                "--decimal-separator '{}' is also the --delimiter, set a different --delimiter",
+                decimal
+            ));
+        }
+
+        if Some(decimal) == self.thousands_separator {
+            return Err(format!(
+                "--decimal-separator '{}' is also the --thousands-separator",
+                decimal
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Transactions read from an input in either format.
+type Transactions<'a> =
+    Box<dyn Iterator<Item = Result<(Transaction, InputPosition), Box<dyn Error>>> + 'a>;
+
+/// Where in its input a transaction was read from, for error reports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct InputPosition {
+    /// 1-based row, counting a CSV header as row 1.
+    row: usize,
+
+    /// Byte offset of the row, only known for CSV input.
+    byte: Option<u64>,
+}
+
+/// What was read from a single input file.
+#[derive(Debug, Default)]
+struct InputSummary {
+    /// Number of transactions read.
+    transactions: usize,
+
+    /// Clients referenced by any of the transactions read.
+    clients: HashSet<u16>,
+}
+
+impl InputSummary {
+    fn record(&mut self, transaction: &Transaction) {
+        self.transactions += 1;
+        self.clients.insert(transaction.affected_client());
+    }
+}
+
+/// Applies a transaction read from the input. Rejected transactions are
+/// skipped, as the spec allows, and only reported with `--log-rejected`
+/// since partner files routinely contain them.
+fn apply(args: &Args, ledger: &mut Ledger, transaction: &Transaction, position: InputPosition) {
+    let outflow;
+    let transaction = match transaction.tx_type {
+        TransactionType::Deposit if args.withdrawal_only => return,
+        TransactionType::Deposit => transaction,
+        TransactionType::Withdrawal if args.deposit_only => return,
+        TransactionType::Withdrawal if args.withdrawal_only => {
+            outflow = Transaction {
+                tx_type: TransactionType::Deposit,
+                ..transaction.clone()
+            };
+            &outflow
+        }
+        TransactionType::Withdrawal => transaction,
+        _ if args.deposit_only || args.withdrawal_only => return,
+        _ => transaction,
+    };
+
+    let (result, trace) = explain::apply_explained(
+        transaction,
+        ledger,
+        args.explain,
+        args.amount_format(),
+        args.precision,
     );
 
-    for transaction in rdr.deserialize::<crate::transaction::Transaction>() {
-        // We don't care about the errors here.
-        let _ = transaction
-            .expect("Failed to parse transaction.")
-            .append_to(&mut ledger);
+    if let Some(trace) = trace {
+        eprint!("{}", trace);
+    }
+
+    if let Err(err) = result {
+        log_rejected(args, transaction, &err, position);
+    }
+}
+
+/// Reports a transaction which failed to apply in the `--error-format`,
+/// if rejections are being logged.
+fn log_rejected(
+    args: &Args,
+    transaction: &Transaction,
+    err: &TransactionError,
+    position: InputPosition,
+) {
+    match args.error_format.unwrap_or(ErrorFormat::Text) {
+        ErrorFormat::Json => eprintln!("{}", rejected_json(transaction, err, position)),
+        ErrorFormat::Text if args.log_rejected => match position.byte {
+            Some(byte) => eprintln!(
+                "tx {} rejected at byte {}: {}",
+                transaction.tx_id, byte, err
+            ),
+            None => eprintln!("tx {} rejected: {}", transaction.tx_id, err),
+        },
+        ErrorFormat::Text => {}
+    }
+}
+
+/// Reverses each transaction read from `reader`, last first, so that
+/// applying a file and then undoing it leaves the accounts as they were.
+/// Rows which can't be reversed, eg. disputes, are rejected.
+fn undo<R: Read>(args: &Args, ledger: &mut Ledger, reader: R) -> Result<(), Box<dyn Error>> {
+    let transactions = args.transactions(reader)?.collect::<Result<Vec<_>, _>>()?;
+
+    for (transaction, position) in transactions.iter().rev() {
+        if let Err(err) = transaction.reverse_from(ledger) {
+            log_rejected(args, transaction, &err, *position);
+        }
+    }
+
+    Ok(())
+}
+
+/// A single line JSON object describing a rejected transaction.
+fn rejected_json(
+    transaction: &Transaction,
+    err: &TransactionError,
+    position: InputPosition,
+) -> String {
+    serde_json::to_string(&RejectedTransaction {
+        tx: transaction.tx_id,
+        client: transaction.client_id,
+        tx_type: transaction.tx_type.name(),
+        error: err.name(),
+        row: position.row,
+        byte: position.byte,
+    })
+    .expect("Serializing a rejected transaction can't fail.")
+}
+
+/// How many transactions are applied between checks of `--max-memory`.
+const MEMORY_CHECK_INTERVAL: usize = 1024;
+
+/// The ledger's estimated memory use passed `--max-memory`.
+#[derive(Debug)]
+struct MemoryLimitExceeded {
+    estimate: usize,
+    limit_mb: usize,
+}
+
+impl std::fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "estimated memory use of {} MB exceeds --max-memory {} MB",
+            self.estimate >> 20,
+            self.limit_mb
+        )
+    }
+}
+
+impl Error for MemoryLimitExceeded {}
+
+/// Fails if the ledger has outgrown `--max-memory`, checked once every
+/// `MEMORY_CHECK_INTERVAL` transactions read.
+fn check_memory(args: &Args, ledger: &Ledger, read: usize) -> Result<(), MemoryLimitExceeded> {
+    let Some(limit_mb) = args
+        .max_memory
+        .filter(|_| read.is_multiple_of(MEMORY_CHECK_INTERVAL))
+    else {
+        return Ok(());
+    };
+
+    let estimate = ledger.estimated_memory();
+
+    if estimate > limit_mb << 20 {
+        return Err(MemoryLimitExceeded { estimate, limit_mb });
+    }
+
+    Ok(())
+}
+
+/// Exits with the error if processing stopped for `--max-memory`, rather
+/// than reporting it as bad input.
+fn exit_on_memory_limit(err: Box<dyn Error>) -> Box<dyn Error> {
+    if let Some(err) = err.downcast_ref::<MemoryLimitExceeded>() {
+        eprintln!("Aborting: {}.", err);
+        std::process::exit(1);
+    }
+
+    err
+}
+
+/// Applies up to `limit` transactions read from `reader` to the ledger.
+fn process<R: Read>(
+    args: &Args,
+    ledger: &mut Ledger,
+    reader: R,
+    limit: usize,
+) -> Result<InputSummary, Box<dyn Error>> {
+    let mut summary = InputSummary::default();
+
+    let transactions = args.transactions(reader)?.skip(args.skip_rows).take(limit);
+
+    for transaction in until_stopped(transactions, &INTERRUPTED) {
+        let (transaction, position) = transaction?;
+        summary.record(&transaction);
+
+        apply(args, ledger, &transaction, position);
+        check_memory(args, ledger, summary.transactions)?;
+    }
+
+    Ok(summary)
+}
+
+/// Applies the rows of a `document` read by `--follow` like `process`,
+/// counting them on from the `read` rows before so that `--limit` and the
+/// rows reported are for the whole file. Unlike `process` a row which
+/// can't be parsed is skipped, reported with `--log-rejected`, so that
+/// following carries on.
+fn process_followed(
+    args: &Args,
+    ledger: &mut Ledger,
+    document: &[u8],
+    read: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    let limit = args.limit.map_or(usize::MAX, |limit| limit - *read);
+    let offset = *read;
+
+    for transaction in until_stopped(args.transactions(document)?.take(limit), &INTERRUPTED) {
+        *read += 1;
+
+        match transaction {
+            Ok((transaction, position)) => {
+                // Byte offsets are into the document, not the file.
+                let position = InputPosition {
+                    row: position.row + offset,
+                    byte: None,
+                };
+
+                apply(args, ledger, &transaction, position);
+                check_memory(args, ledger, *read)?;
+            }
+            Err(err) if args.log_rejected => eprintln!("Skipping malformed row: {}", err),
+            Err(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Tallies the type of each row read from `reader` into `counts`, indexed
+/// by type code less one, with unknown types last. Nothing is applied.
+fn count_types<R: Read>(
+    args: &Args,
+    reader: R,
+    counts: &mut [usize; TransactionType::ALL.len() + 1],
+) -> Result<(), Box<dyn Error>> {
+    let mut transactions = args.transaction_reader(reader)?;
+
+    while let Some(tx_type) = transactions.next_type() {
+        counts[tx_type?.map_or(TransactionType::ALL.len(), |tx_type| {
+            tx_type.code() as usize - 1
+        })] += 1;
+    }
+
+    Ok(())
+}
+
+/// Rows for the counts from `count_types`, omitting unknown types unless
+/// there were any.
+fn type_counts(counts: &[usize; TransactionType::ALL.len() + 1]) -> Vec<TypeCount> {
+    let mut rows: Vec<TypeCount> = TransactionType::ALL
+        .into_iter()
+        .zip(counts)
+        .map(|(tx_type, &count)| TypeCount {
+            tx_type: tx_type.name(),
+            count,
+        })
+        .collect();
+
+    if let Some(&unknown) = counts.last().filter(|&&unknown| unknown > 0) {
+        rows.push(TypeCount {
+            tx_type: "unknown",
+            count: unknown,
+        });
+    }
+
+    rows
+}
+
+/// Set on Ctrl-C to stop reading transactions, so that the accounts as of
+/// the last transaction applied can be written.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code of an interrupted run, as shells report for a process
+/// killed by `SIGINT`.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Yields `items` until `stop` is set. The flag is checked before each
+/// item is read, so nothing is read and then dropped.
+fn until_stopped<'a, T: 'a>(
+    mut items: impl Iterator<Item = T> + 'a,
+    stop: &'a AtomicBool,
+) -> impl Iterator<Item = T> + 'a {
+    std::iter::from_fn(move || {
+        if stop.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        items.next()
+    })
+}
+
+/// Applies the transactions read from `reader` in date order, writing the
+/// accounts as of the end of each date to `<dir>/<date>.csv`.
+///
+/// Every transaction has to be buffered so they can be sorted by date.
+/// Transactions on the same date keep their input order, and any without
+/// a date are applied before the first dated transaction.
+fn process_daily<R: Read>(
+    args: &Args,
+    ledger: &mut Ledger,
+    reader: R,
+    limit: usize,
+    dir: &Path,
+) -> Result<InputSummary, Box<dyn Error>> {
+    let mut summary = InputSummary::default();
+
+    let transactions = args.transactions(reader)?.skip(args.skip_rows).take(limit);
+    let mut transactions =
+        until_stopped(transactions, &INTERRUPTED).collect::<Result<Vec<_>, _>>()?;
+
+    transactions.sort_by_key(|(transaction, _)| transaction.date);
+    std::fs::create_dir_all(dir)?;
+
+    let mut transactions = transactions.into_iter().peekable();
+
+    while let Some((transaction, position)) = transactions.next() {
+        summary.record(&transaction);
+
+        apply(args, ledger, &transaction, position);
+        check_memory(args, ledger, summary.transactions)?;
+
+        let next_date = transactions.peek().map(|(next, _)| next.date);
+
+        if let Some(date) = transaction
+            .date
+            .filter(|date| next_date != Some(Some(*date)))
+        {
+            let snapshot = File::create(dir.join(format!("{}.csv", date)))?;
+            output::write_csv(
+                scaled_accounts(args, ledger),
+                snapshot,
+                args.quote_style.into(),
+            )?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Records which input first introduced each of `clients`, returning the
+/// clients, in ascending order, which an earlier input already introduced.
+fn record_client_origins(
+    origins: &mut HashMap<u16, usize>,
+    input: usize,
+    clients: &HashSet<u16>,
+) -> Vec<u16> {
+    let mut conflicts: Vec<u16> = clients
+        .iter()
+        .filter(|client_id| {
+            origins
+                .get(client_id)
+                .is_some_and(|&origin| origin != input)
+        })
+        .copied()
+        .collect();
+
+    for &client_id in clients {
+        origins.entry(client_id).or_insert(input);
+    }
+
+    conflicts.sort_unstable();
+    conflicts
+}
+
+/// The partner of each client, that of the input which first introduced
+/// it, as recorded by `record_client_origins`.
+fn client_partners<'a>(args: &'a Args, origins: &HashMap<u16, usize>) -> HashMap<u16, &'a str> {
+    origins
+        .iter()
+        .filter_map(|(&client_id, &input)| {
+            args.partner_id
+                .get(input)
+                .map(|partner| (client_id, partner.as_str()))
+        })
+        .collect()
+}
+
+/// Describes each of `conflicts`, clients of input `input` which an
+/// earlier input introduced as returned by `record_client_origins`, whose
+/// first partner isn't the partner of `input`. Described in the same
+/// ascending order of client ID.
+fn partner_conflicts(
+    args: &Args,
+    origins: &HashMap<u16, usize>,
+    input: usize,
+    conflicts: &[u16],
+) -> Vec<String> {
+    let Some(partner) = args.partner_id.get(input) else {
+        return vec![];
+    };
+
+    conflicts
+        .iter()
+        .filter_map(|&client_id| {
+            let first = &args.partner_id[origins[&client_id]];
+
+            (first != partner).then(|| {
+                format!(
+                    "client {} from partner {} was first seen from partner {}",
+                    client_id, partner, first
+                )
+            })
+        })
+        .collect()
+}
+
+/// The ledger's accounts with funds shifted back into the input's amount scale.
+fn scaled_accounts(args: &Args, ledger: &Ledger) -> Vec<Account> {
+    let format = args.amount_format();
+
+    ledger
+        .export_accounts()
+        .into_iter()
+        .map(|account| Account {
+            available_funds: format.rescale(account.available_funds),
+            held_funds: format.rescale(account.held_funds),
+            shortfall: format.rescale(account.shortfall),
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+            ..account
+        })
+        .collect()
+}
+
+/// Tolerance when comparing computed funds against an expected output.
+const COMPARE_EPSILON: f64 = 1e-9;
+
+/// Reads accounts as written by this tool's CSV output, checking each
+/// `total` column is the account's available plus held funds, see
+/// `--total-mismatch`. The output has no shortfall column, so an account
+/// with no available funds and a lower total is read as having the
+/// difference as its shortfall, which is the only way one is written.
+fn read_accounts<R: Read>(
+    reader: R,
+    total_mismatch: CheckAction,
+) -> Result<BTreeMap<u16, Account>, Box<dyn Error>> {
+    let mut rdr = csv_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let total_index = headers.iter().position(|header| header == "total");
+    let mut accounts = BTreeMap::new();
+
+    for record in rdr.records() {
+        let record = record?;
+        let mut account: Account = record.deserialize(Some(&headers))?;
+
+        if let Some(total) = total_index.and_then(|index| record.get(index)) {
+            let total: f64 = total.parse().map_err(|err| {
+                format!("client {} total {:?}: {}", account.client_id, total, err)
+            })?;
+            let shortfall = account.total() - total;
+
+            if account.available_funds == 0.0 && shortfall > COMPARE_EPSILON {
+                account.shortfall = shortfall;
+            } else if shortfall.abs() > COMPARE_EPSILON {
+                let mismatch = format!(
+                    "client {} total {} isn't available plus held, recomputed as {}",
+                    account.client_id,
+                    total,
+                    account.total()
+                );
+
+                match total_mismatch {
+                    CheckAction::Warn => eprintln!("{}", mismatch),
+                    CheckAction::Error => return Err(mismatch.into()),
+                }
+            }
+        }
+
+        accounts.insert(account.client_id, account);
+    }
+
+    Ok(accounts)
+}
+
+/// Reads client IDs separated by whitespace, usually one per line.
+fn read_client_ids<R: Read>(mut reader: R) -> Result<BTreeSet<u16>, Box<dyn Error>> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
+
+    contents
+        .split_whitespace()
+        .map(|id| {
+            id.parse()
+                .map_err(|err| format!("invalid client ID {:?}: {}", id, err).into())
+        })
+        .collect()
+}
+
+/// How far below zero held funds may be, as float residue left by
+/// releasing them, before `--assert-no-negative-held` fails.
+const NEGATIVE_HELD_EPSILON: f64 = 1e-9;
+
+/// Clients whose held funds are below zero, beyond float residue.
+fn negative_held_clients(ledger: &Ledger) -> Vec<u16> {
+    ledger
+        .accounts
+        .values()
+        .filter(|account| account.held_funds < -NEGATIVE_HELD_EPSILON)
+        .map(|account| account.client_id)
+        .collect()
+}
+
+/// Reads the ledger saved for `--resume-from`, with the options given.
+fn resumed_ledger<R: Read>(args: &Args, reader: R) -> Result<Ledger, Box<dyn Error>> {
+    let mut ledger = state::read_state(reader)?;
+    ledger.options = args.ledger_options();
+    ledger.registered_clients = args.registered_clients();
+    ledger.seen_tx_ids = args.seen_tx_ids();
+
+    if let Some(seen) = &mut ledger.seen_tx_ids {
+        for &tx_id in ledger.transactions.keys() {
+            seen.insert(tx_id);
+        }
+    }
+
+    Ok(ledger)
+}
+
+/// Writes the ledger to the `--save-state` file, if given.
+fn save_state(args: &Args, ledger: &Ledger) {
+    if let Some(path) = &args.save_state {
+        let file = File::create(path).expect("Failed to create state file.");
+        state::write_state(ledger, file).expect("Failed to write state file.");
+    }
+}
+
+/// Replays `transactions` into a fresh ledger and describes each client
+/// whose account differs from the `snapshot` accounts file.
+fn verify_snapshot<S: Read, T: Read>(
+    args: &Args,
+    snapshot: S,
+    transactions: T,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let expected = read_accounts(snapshot, args.total_mismatch)?;
+
+    let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+    ledger.options = args.ledger_options();
+    process(args, &mut ledger, transactions, usize::MAX)?;
+
+    Ok(compare_accounts(expected, scaled_accounts(args, &ledger)))
+}
+
+/// Describes each client, in ascending order, whose account differs
+/// between `expected` and the `actual` output accounts.
+fn compare_accounts(expected: BTreeMap<u16, Account>, actual: Vec<Account>) -> Vec<String> {
+    let describe = |account: &Account| {
+        format!(
+            "available {}, held {}, total {}, locked {}",
+            format_funds(account.available_funds, None),
+            format_funds(account.held_funds, None),
+            format_funds(account.total(), None),
+            account.is_locked()
+        )
+    };
+
+    let expected = Ledger::new(HashMap::new(), expected);
+    let actual = Ledger::new(
+        HashMap::new(),
+        actual
+            .into_iter()
+            .map(|account| {
+                // Every inactive status is written, and so read back, as locked.
+                let status = if account.is_locked() {
+                    AccountStatus::Locked
+                } else {
+                    AccountStatus::Active
+                };
+
+                (account.client_id, Account { status, ..account })
+            })
+            .collect(),
+    );
+
+    actual
+        .diff(&expected, COMPARE_EPSILON)
+        .into_iter()
+        .map(|client_id| {
+            match (
+                expected.accounts.get(&client_id),
+                actual.accounts.get(&client_id),
+            ) {
+                (Some(expected), Some(actual)) => format!(
+                    "client {}: expected {}, got {}",
+                    client_id,
+                    describe(expected),
+                    describe(actual)
+                ),
+                (Some(_), None) => {
+                    format!("client {}: missing from the computed accounts", client_id)
+                }
+                (None, _) => format!("client {}: missing from the expected accounts", client_id),
+            }
+        })
+        .collect()
+}
+
+/// Writes the accounts changed since the last call to the change feed.
+fn write_changes(args: &Args, ledger: &mut Ledger, feed: &mut csv::Writer<Stdout>) {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let format = args.amount_format();
+
+    for client_id in ledger.take_changed() {
+        let Some(account) = ledger.accounts.get(&client_id) else {
+            continue;
+        };
+
+        if !args.selects(client_id) {
+            continue;
+        }
+
+        let change = AccountChange {
+            available: format.rescale(account.available_funds),
+            held: format.rescale(account.held_funds),
+            total: format.rescale(account.total()),
+            ..AccountChange::new(timestamp_ms, account)
+        };
+
+        feed.serialize(change).expect("Failed to write to stdout.");
+    }
+
+    feed.flush().expect("Failed to write to stdout.");
+}
+
+/// Writes the ledger's accounts to stdout, annotated if `--verbose` is set.
+/// Only the accounts of clients selected with `--client` are written,
+/// and with `--top` only the accounts with the highest total funds.
+fn write_accounts(args: &Args, ledger: &Ledger, partners: &HashMap<u16, &str>) {
+    let mut accounts: Vec<Account> = scaled_accounts(args, ledger)
+        .into_iter()
+        .filter(|account| args.selects(account.client_id))
+        .collect();
+
+    if let Some(top) = args.top {
+        accounts.sort_by(output::compare_by_total);
+        accounts.truncate(top);
+    }
+
+    let Some(paths) = &args.split_output else {
+        write_selected(args, ledger, &accounts, partners, std::io::stdout().lock())
+            .expect("Failed to write to stdout.");
+        return;
+    };
+
+    let (locked, active): (Vec<Account>, Vec<Account>) =
+        accounts.into_iter().partition(Account::is_locked);
+
+    for (path, accounts) in paths.iter().zip([active, locked]) {
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let file = File::create(path).expect("Failed to create split output file.");
+        write_selected(args, ledger, &accounts, partners, file)
+            .expect("Failed to write split output file.");
+    }
+}
+
+/// Writes the chosen accounts, in the shape asked for, to `writer`.
+fn write_selected<W: Write>(
+    args: &Args,
+    ledger: &Ledger,
+    accounts: &[Account],
+    partners: &HashMap<u16, &str>,
+    mut writer: W,
+) -> Result<(), Box<dyn Error>> {
+    if args.cashflow {
+        let format = args.amount_format();
+        let mut flows: HashMap<u16, CashFlow> = output::cash_flows(ledger)
+            .into_iter()
+            .map(|flow| (flow.client, flow))
+            .collect();
+
+        write_rows(
+            args,
+            accounts
+                .iter()
+                .filter_map(|account| flows.remove(&account.client_id))
+                .map(|flow| CashFlow {
+                    deposited: format.rescale(flow.deposited),
+                    withdrawn: format.rescale(flow.withdrawn),
+                    net: format.rescale(flow.net),
+                    total: format.rescale(flow.total),
+                    ..flow
+                }),
+            &mut writer,
+        )?;
+    } else if args.verbose {
+        let format = args.amount_format();
+        let mut verbose: HashMap<u16, VerboseAccount> = output::verbose_accounts(ledger)
+            .into_iter()
+            .map(|account| (account.client, account))
+            .collect();
+
+        write_rows(
+            args,
+            accounts
+                .iter()
+                .filter_map(|account| verbose.remove(&account.client_id))
+                .map(|account| VerboseAccount {
+                    available: format.rescale(account.available),
+                    held: format.rescale(account.held),
+                    total: format.rescale(account.total),
+                    ..account
+                }),
+            &mut writer,
+        )?;
+    } else if let Some(columns) = &args.output_columns {
+        write_rows(
+            args,
+            accounts
+                .iter()
+                .map(|account| AccountColumns { account, columns }),
+            &mut writer,
+        )?;
+    } else if !args.partner_id.is_empty() {
+        write_rows(
+            args,
+            accounts.iter().map(|account| PartnerAccount {
+                account,
+                partner: partners.get(&account.client_id).copied(),
+            }),
+            &mut writer,
+        )?;
+    } else {
+        write_rows(args, accounts, &mut writer)?;
+    }
+
+    if args.summary_footer && args.format.unwrap_or(OutputFormat::Csv) == OutputFormat::Csv {
+        output::write_summary_footer(accounts, &mut writer)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the output rows to stdout in the configured format.
+fn write_output<T: Serialize>(args: &Args, rows: impl IntoIterator<Item = T>) {
+    write_rows(args, rows, std::io::stdout().lock()).expect("Failed to write to stdout.");
+}
+
+/// Writes the output rows to `writer` in the configured format.
+fn write_rows<T: Serialize, W: Write>(
+    args: &Args,
+    rows: impl IntoIterator<Item = T>,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    match args.format.unwrap_or(OutputFormat::Csv) {
+        OutputFormat::Csv => output::write_csv(rows, writer, args.quote_style.into())?,
+        OutputFormat::Json => output::write_json(rows, writer, args.pretty)?,
+        OutputFormat::Table => output::write_table(rows, writer, args.precision)?,
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let mut args = Args::parse();
+
+    match args.command {
+        Some(Command::Generate {
+            count,
+            clients,
+            seed,
+        }) => {
+            generate::generate(std::io::stdout().lock(), count, clients, seed)
+                .expect("Failed to write to stdout.");
+            return;
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Args::command(),
+                "toy-ledger",
+                &mut std::io::stdout(),
+            );
+            return;
+        }
+        Some(Command::VerifySnapshot {
+            ref snapshot,
+            ref transactions,
+        }) => {
+            let (snapshot, transactions) = (snapshot.clone(), transactions.clone());
+            let config = Config::discover(None).expect("Failed to read config file.");
+            args.apply_config(config);
+
+            let mismatches = verify_snapshot(
+                &args,
+                File::open(snapshot).expect("Failed to read snapshot."),
+                File::open(transactions).expect("Failed to read transactions."),
+            )
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to verify snapshot: {}", err);
+                std::process::exit(1);
+            });
+
+            if !mismatches.is_empty() {
+                for mismatch in mismatches {
+                    eprintln!("{}", mismatch);
+                }
+                std::process::exit(1);
+            }
+            return;
+        }
+        #[cfg(feature = "serve")]
+        Some(Command::Serve { ref addr }) => {
+            let addr = addr.clone();
+            let config = Config::discover(None).expect("Failed to read config file.");
+            args.apply_config(config);
+
+            let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+            ledger.options = args.ledger_options();
+
+            eprintln!("Listening on http://{}", addr);
+            serve::serve(&addr, &mut ledger).expect("Failed to start server.");
+            return;
+        }
+        None => {}
+    }
+
+    let config = Config::discover(args.config.as_deref()).expect("Failed to read config file.");
+    args.apply_config(config);
+
+    if let Err(err) = args.check_separators() {
+        Args::command()
+            .error(clap::ErrorKind::ArgumentConflict, err)
+            .exit();
+    }
+
+    // A second Ctrl-C exits straight away, eg. while waiting on stdin.
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .expect("Failed to set Ctrl-C handler.");
+
+    if args.follow {
+        let path = match args.csv_filenames.as_slice() {
+            [path] => path,
+            _ => {
+                eprintln!("--follow requires exactly one input file.");
+                std::process::exit(1);
+            }
+        };
+
+        if args.input_format != InputFormat::Csv {
+            eprintln!("--follow only supports CSV input.");
+            std::process::exit(1);
+        }
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options = args.ledger_options();
+        ledger.registered_clients = args.registered_clients();
+        ledger.seen_tx_ids = args.seen_tx_ids();
+
+        let mut feed = args.changes_only.then(|| {
+            ledger.track_changes();
+            csv::WriterBuilder::new()
+                .quote_style(args.quote_style.into())
+                .from_writer(std::io::stdout())
+        });
+
+        let mut read = 0;
+
+        follow::follow(
+            path,
+            &mut ledger,
+            Duration::from_millis(args.follow_interval),
+            &INTERRUPTED,
+            |ledger, document| process_followed(&args, ledger, document, &mut read),
+            |ledger| match &mut feed {
+                Some(feed) => write_changes(&args, ledger, feed),
+                None => write_accounts(&args, ledger, &HashMap::new()),
+            },
+        )
+        .map_err(exit_on_memory_limit)
+        .expect("Failed to follow input file.");
+
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        return;
+    }
+
+    let inputs: Vec<(String, Box<dyn Read>)> = if args.csv_filenames.is_empty() {
+        vec![("stdin".to_string(), Box::new(std::io::stdin()))]
+    } else {
+        args.csv_filenames
+            .iter()
+            .map(|filename| -> (String, Box<dyn Read>) {
+                let file = File::open(filename).expect("Failed to read input file.");
+                (filename.display().to_string(), Box::new(file))
+            })
+            .collect()
+    };
+
+    if args.count_only {
+        if args.input_format != InputFormat::Csv {
+            eprintln!("--count-only only supports CSV input.");
+            std::process::exit(1);
+        }
+
+        let mut counts = [0; TransactionType::ALL.len() + 1];
+        for (_, file) in inputs {
+            count_types(&args, file, &mut counts).expect("Failed to parse transaction.");
+        }
+
+        write_output(&args, type_counts(&counts));
+        return;
+    }
+
+    if !args.partner_id.is_empty() && args.partner_id.len() != inputs.len() {
+        eprintln!("--partner-id must be given once per input file.");
+        std::process::exit(1);
+    }
+
+    if args.skip_rows > 0 && args.csv_filenames.len() > 1 {
+        eprintln!("--skip-rows requires a single input file.");
+        std::process::exit(1);
+    }
+
+    let mut ledger = match &args.resume_from {
+        Some(path) => {
+            let file = File::open(path).expect("Failed to read state to resume from.");
+            resumed_ledger(&args, file).expect("Failed to parse state to resume from.")
+        }
+        None => {
+            let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+            ledger.options = args.ledger_options();
+            ledger.registered_clients = args.registered_clients();
+            ledger.seen_tx_ids = args.seen_tx_ids();
+            ledger
+        }
+    };
+
+    #[cfg(feature = "watch")]
+    if let Some(dir) = &args.watch {
+        watch::watch(
+            dir,
+            &mut ledger,
+            &INTERRUPTED,
+            |ledger, file| {
+                process(&args, ledger, file, usize::MAX).map_err(exit_on_memory_limit)?;
+                Ok(())
+            },
+            |ledger| {
+                write_accounts(&args, ledger, &HashMap::new());
+                save_state(&args, ledger);
+            },
+        )
+        .expect("Failed to watch input directory.");
+
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        return;
+    }
+
+    let mut count = 0;
+    let mut client_origins = HashMap::new();
+
+    for (index, (name, file)) in inputs.into_iter().enumerate() {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let limit = args.limit.map_or(usize::MAX, |limit| limit - count);
+
+        let summary = match &args.daily_snapshots {
+            Some(dir) => process_daily(&args, &mut ledger, file, limit, dir)
+                .map_err(exit_on_memory_limit)
+                .expect("Failed to write daily snapshots."),
+            None => process(&args, &mut ledger, file, limit)
+                .map_err(exit_on_memory_limit)
+                .expect("Failed to parse transaction."),
+        };
+
+        count += summary.transactions;
+
+        let conflicts = record_client_origins(&mut client_origins, index, &summary.clients);
+
+        for warning in partner_conflicts(&args, &client_origins, index, &conflicts) {
+            eprintln!("Warning: {} in {}.", warning, name);
+        }
+
+        if args.deny_duplicate_clients_across_files && !conflicts.is_empty() {
+            eprintln!(
+                "Clients {:?} in {} already appeared in an earlier file.",
+                conflicts, name
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let partners = client_partners(&args, &client_origins);
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        eprintln!("Interrupted after {} transactions.", count);
+        write_accounts(&args, &ledger, &partners);
+        save_state(&args, &ledger);
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    if args.require_rows && count == 0 {
+        eprintln!("No transactions found in input.");
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &args.undo {
+        let file = File::open(path).expect("Failed to open file to undo.");
+        undo(&args, &mut ledger, file).expect("Failed to parse transaction to undo.");
+    }
+
+    if let Some(client) = args.sweep_locked_to {
+        ledger
+            .sweep_locked(client)
+            .expect("Failed to sweep locked accounts.");
+    }
+
+    if let Some(rate) = args.held_fee {
+        ledger
+            .charge_held_fee(rate)
+            .expect("Failed to charge held funds fee.");
+    }
+
+    if args.assert_no_negative_held {
+        let clients = negative_held_clients(&ledger);
+
+        if !clients.is_empty() {
+            eprintln!("Clients {:?} have negative held funds.", clients);
+            std::process::exit(1);
+        }
+    }
+
+    write_accounts(&args, &ledger, &partners);
+    save_state(&args, &ledger);
+
+    if let Some(path) = &args.audit_json {
+        let mut records = output::audit_records(&ledger);
+        for record in &mut records {
+            record.amount = record
+                .amount
+                .map(|amount| args.amount_format().rescale(amount));
+        }
+
+        let file = File::create(path).expect("Failed to create audit file.");
+        output::write_json(records, file, args.pretty).expect("Failed to write audit file.");
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.sqlite {
+        ledger
+            .write_sqlite(path)
+            .expect("Failed to write SQLite database.");
+    }
+
+    if args.reconcile {
+        let mismatched = ledger.reconcile_holds();
+
+        if !mismatched.is_empty() {
+            eprintln!(
+                "Held funds don't match open disputes for clients: {:?}",
+                mismatched
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.compare {
+        let file = File::open(path).expect("Failed to read expected output file.");
+        let expected = read_accounts(file, args.total_mismatch).unwrap_or_else(|err| {
+            eprintln!("Failed to parse expected output file: {}", err);
+            std::process::exit(1);
+        });
+        let mismatches = compare_accounts(expected, scaled_accounts(&args, &ledger));
+
+        if !mismatches.is_empty() {
+            for mismatch in mismatches {
+                eprintln!("{}", mismatch);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+    use std::path::PathBuf;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use clap::Parser;
+
+    use crate::config::Config;
+    use crate::explain::apply_explained;
+    use crate::follow::FollowBuffer;
+    use crate::generate::generate;
+    use crate::state;
+    use crate::{
+        client_partners, compare_accounts, count_types, negative_held_clients, partner_conflicts,
+        process, process_daily, process_followed, read_accounts, read_client_ids,
+        record_client_origins, rejected_json, resumed_ledger, scaled_accounts, type_counts, undo,
+        until_stopped, verify_snapshot, write_accounts, write_selected, Args, CheckAction, Command,
+        ErrorFormat, InputPosition, MemoryLimitExceeded, OutputFormat, RoundingMode,
+    };
+    use toy_ledger::account::{format_funds, Account, AccountSnapshot, AccountStatus, Rounding};
+    use toy_ledger::bloom::BloomFilter;
+    use toy_ledger::ledger::{Ledger, LedgerOptions, TransactionHook};
+    use toy_ledger::output::{self, AccountColumn, AccountColumns, CashFlow, VerboseAccount};
+    use toy_ledger::transaction::{
+        dedup_transactions, exact_decimal, json_transactions, AmountFormat, BadHeader, Date,
+        RoundTripMismatch, Transaction, TransactionError, TransactionReader, TransactionType,
+    };
+
+    /// Balances are `f64` so exact comparisons are fragile once
+    /// arithmetic is involved, compare funds within `epsilon` instead.
+    fn assert_account_approx_eq(left: &Account, right: &Account, epsilon: f64) {
+        assert!(
+            left.approx_eq(right, epsilon),
+            "accounts differ by more than {}:\n  left: {:?}\n right: {:?}",
+            epsilon,
+            left,
+            right
+        );
+    }
+
+    fn create_test_ledger(contents: &str) -> Result<Ledger, TransactionError> {
+        create_test_ledger_with_options(contents, LedgerOptions::default())
+    }
+
+    fn create_test_ledger_with_options(
+        contents: &str,
+        options: LedgerOptions,
+    ) -> Result<Ledger, TransactionError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options = options;
+
+        for transaction in rdr.deserialize::<Transaction>() {
+            transaction.unwrap().append_to(&mut ledger)?;
+        }
+
+        Ok(ledger)
+    }
+
+    #[test]
+    fn account_balances_should_add_up() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1
+deposit,1,3,2
+deposit,2,5,9
+withdrawal,2,6,5
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 3.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+
+        assert_eq!(
+            ledger.accounts.get(&2).unwrap(),
+            &Account {
+                client_id: 2,
+                available_funds: 4.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn disputes_of_unknown_transactions_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1
+deposit,2,2,2
+deposit,1,3,2
+dispute,1,5,
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::TransactionNotFound);
+    }
+
+    #[test]
+    fn accounts_should_iterate_in_client_order() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,300,1,1
+deposit,7,2,2
+deposit,65535,3,3
+deposit,1,4,4
+deposit,42,5,5
+",
+        )
+        .unwrap();
+
+        assert!(ledger
+            .accounts
+            .values()
+            .map(|account| account.client_id)
+            .eq([1, 7, 42, 300, 65535]));
+    }
+
+    #[test]
+    fn appending_should_return_the_account_balances() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,20
+",
+        )
+        .unwrap();
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client_id: 1,
+            tx_id: 2,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        assert_eq!(
+            dispute.append_to(&mut ledger),
+            Ok(AccountSnapshot {
+                client_id: 1,
+                available_funds: 100.0,
+                held_funds: 20.0,
+                total_funds: 120.0,
+                status: AccountStatus::Active,
+            })
+        );
+    }
+
+    #[test]
+    fn held_fees_should_be_charged_from_available_funds() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+deposit,2,3,80
+dispute,1,2,
+",
+        )
+        .unwrap();
+
+        ledger.charge_held_fee(0.1).unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                available_funds: 95.0,
+                held_funds: 50.0,
+                open_disputes: 1,
+                transaction_count: 2,
+                ..Account::new(1)
+            }
+        );
+        assert_eq!(ledger.accounts.get(&2).unwrap().available_funds, 80.0);
+        assert_eq!(
+            ledger.transactions.get(&u32::MAX),
+            Some(&Transaction {
+                tx_type: TransactionType::Withdrawal,
+                client_id: 1,
+                tx_id: u32::MAX,
+                amount: Some(5.0),
+                disputed: false,
+                settled: -5.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            })
+        );
+        assert!(ledger.reconcile_holds().is_empty());
+        assert!(Args::try_parse_from(["toy-ledger", "--held-fee", "-0.1"]).is_err());
+    }
+
+    #[test]
+    fn valid_disputes_should_hold_funds() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1
+deposit,1,3,2
+dispute,1,1,
+",
+        )
+        .unwrap();
+
+        assert!(ledger.accounts.values().eq(vec![&Account {
+            client_id: 1,
+            available_funds: 2.0,
+            held_funds: 1.0,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 1,
+            transaction_count: 2,
+        }]));
+    }
+
+    #[test]
+    fn deposits_into_a_locked_account_should_fail_by_default() {
+        let result = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+chargeback,1,1,
+deposit,1,2,5
+",
+        );
+
+        assert_eq!(result.unwrap_err(), TransactionError::AccountLocked);
+    }
+
+    #[test]
+    fn deposits_into_a_locked_account_should_succeed_when_allowed() {
+        let options = LedgerOptions {
+            allow_deposit_when_locked: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,20
+dispute,1,1,
+chargeback,1,1,
+deposit,1,3,5
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 25.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 3,
+            }
+        );
+
+        for row in ["withdrawal,1,4,5", "dispute,1,2,"] {
+            let result = create_test_ledger_with_options(
+                &format!(
+                    "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,20
+dispute,1,1,
+chargeback,1,1,
+{}
+",
+                    row
+                ),
+                options,
+            );
+
+            assert_eq!(result.unwrap_err(), TransactionError::AccountLocked);
+        }
+    }
+
+    #[test]
+    fn disputes_beyond_the_maximum_open_should_fail() {
+        let options = LedgerOptions {
+            max_open_disputes: Some(2),
+            ..LedgerOptions::default()
+        };
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,10
+deposit,1,3,10
+deposit,2,4,10
+dispute,1,1,
+dispute,1,2,
+dispute,2,4,
+";
+
+        let result =
+            create_test_ledger_with_options(&format!("{}dispute,1,3,\n", transactions), options);
+        assert_eq!(result.unwrap_err(), TransactionError::TooManyOpenDisputes);
+
+        // Resolving a dispute makes room for another.
+        let ledger = create_test_ledger_with_options(
+            &format!("{}resolve,1,1,\ndispute,1,3,\n", transactions),
+            options,
+        )
+        .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().open_disputes, 2);
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_beyond_the_maximum_per_client_should_fail() {
+        let options = LedgerOptions {
+            max_tx_per_client: Some(2),
+            ..LedgerOptions::default()
+        };
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,5
+deposit,2,3,10
+dispute,1,1,
+resolve,1,1,
+";
+
+        // Disputes and resolves don't count towards the limit.
+        let ledger = create_test_ledger_with_options(transactions, options).unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().transaction_count, 2);
+
+        for row in ["deposit,1,4,1", "withdrawal,1,4,1"] {
+            let result =
+                create_test_ledger_with_options(&format!("{}{}\n", transactions, row), options);
+            assert_eq!(result.unwrap_err(), TransactionError::RateLimited);
+        }
+    }
+
+    /// Example of a deployment specific rule, refusing large withdrawals.
+    #[derive(Debug)]
+    struct WithdrawalLimit(f64);
+
+    impl TransactionHook for WithdrawalLimit {
+        fn before_apply(
+            &self,
+            transaction: &Transaction,
+            _account: &Account,
+        ) -> Result<(), TransactionError> {
+            match (transaction.tx_type, transaction.amount) {
+                (TransactionType::Withdrawal, Some(amount)) if amount > self.0 => {
+                    Err(TransactionError::Rejected {
+                        reason: format!("withdrawal over {}", self.0),
+                    })
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_should_refuse_transactions_before_funds_move() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,500
+withdrawal,1,2,50
+withdrawal,1,3,200
+";
+        let mut rdr = csv::Reader::from_reader(transactions.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().map(Result::unwrap).collect();
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.hook = Some(Arc::new(WithdrawalLimit(100.0)));
+
+        assert_eq!(
+            ledger.apply_all(transactions),
+            vec![TransactionError::Rejected {
+                reason: "withdrawal over 100".to_string()
+            }]
+        );
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 450.0);
+        assert_eq!(ledger.is_disputed(3), None);
+    }
+
+    #[test]
+    fn transactions_should_only_affect_their_client() {
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            tx_id: 1,
+            client_id: 7,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        assert_eq!(dispute.affected_client(), 7);
+        assert!(dispute.affects_account(7));
+        assert!(!dispute.affects_account(1));
+    }
+
+    #[test]
+    fn reversed_transactions_should_restore_the_accounts() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,30
+deposit,2,3,50
+dispute,2,3,
+";
+        let mut ledger = create_test_ledger(transactions).unwrap();
+        let args = Args::parse_from(["toy-ledger"]);
+
+        undo(&args, &mut ledger, transactions.as_bytes()).unwrap();
+
+        // Client 2's deposit is still disputed so stays put.
+        assert_eq!(ledger.accounts.get(&1).unwrap().total(), 0.0);
+        assert_eq!(ledger.accounts.get(&2).unwrap().held_funds, 50.0);
+        assert_eq!(ledger.is_disputed(1), None);
+        assert_eq!(ledger.is_disputed(3), Some(true));
+    }
+
+    #[test]
+    fn reversing_should_not_leave_available_funds_negative() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,80
+",
+        )
+        .unwrap();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(100.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        assert_eq!(
+            deposit.reverse_from(&mut ledger).unwrap_err(),
+            TransactionError::InsufficientFunds
+        );
+
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            tx_id: 2,
+            amount: Some(80.0),
+            ..deposit.clone()
+        };
+        assert_eq!(
+            withdrawal.reverse_from(&mut ledger).unwrap().total_funds,
+            100.0
+        );
+        assert_eq!(deposit.reverse_from(&mut ledger).unwrap().total_funds, 0.0);
+        assert_eq!(
+            deposit.reverse_from(&mut ledger).unwrap_err(),
+            TransactionError::TransactionNotFound
+        );
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            ..deposit
+        };
+        assert_eq!(
+            dispute.reverse_from(&mut ledger).unwrap_err(),
+            TransactionError::Irreversible
+        );
+    }
+
+    #[test]
+    fn close_should_pay_out_and_lock_the_account() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+close,1,3,
+";
+
+        // Closes aren't accepted unless the ledger allows them.
+        let result = create_test_ledger(transactions);
+        assert_eq!(result.unwrap_err(), TransactionError::Unauthorized);
+
+        let options = LedgerOptions {
+            allow_close: true,
+            ..LedgerOptions::default()
+        };
+
+        let mut ledger = create_test_ledger_with_options(transactions, options).unwrap();
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.status, AccountStatus::Closed);
+        assert_eq!(account.total(), 0.0);
+        assert_eq!(account.locked_by_tx, Some(3));
+
+        // The payout is recorded like any other withdrawal.
+        assert_eq!(ledger.is_disputed(3), Some(false));
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 4,
+            client_id: 1,
+            amount: Some(5.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        assert_eq!(
+            deposit.append_to(&mut ledger).unwrap_err(),
+            TransactionError::AccountLocked
+        );
+    }
+
+    #[test]
+    fn close_should_fail_with_open_disputes() {
+        let options = LedgerOptions {
+            allow_close: true,
+            ..LedgerOptions::default()
+        };
+
+        let result = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+close,1,2,
+",
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::HasOpenDisputes);
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+resolve,1,1,
+close,1,2,
+",
+            options,
+        )
+        .unwrap();
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap().status,
+            AccountStatus::Closed
+        );
+    }
+
+    #[test]
+    fn close_should_fail_for_an_unknown_client() {
+        let options = LedgerOptions {
+            allow_close: true,
+            ..LedgerOptions::default()
+        };
+
+        let mut ledger =
+            create_test_ledger_with_options("type,client,tx,amount\n", options).unwrap();
+        let close = Transaction {
+            tx_type: TransactionType::Close,
+            tx_id: 1,
+            client_id: 7,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        assert_eq!(
+            close.append_to(&mut ledger),
+            Err(TransactionError::UnknownClient)
+        );
+        assert!(ledger.accounts.is_empty());
+
+        // The client can still open an account afterwards.
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 2,
+            amount: Some(10.0),
+            ..close
+        };
+        deposit.append_to(&mut ledger).unwrap();
+        assert_eq!(ledger.accounts[&7].status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn strict_dispute_amounts_should_reject_disputes_with_amounts() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,10
+";
+
+        // The amount is ignored by default.
+        let ledger = create_test_ledger(transactions).unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().held_funds, 10.0);
+
+        let options = LedgerOptions {
+            strict_dispute_amounts: true,
+            ..LedgerOptions::default()
+        };
+
+        let result = create_test_ledger_with_options(transactions, options);
+        assert_eq!(result.unwrap_err(), TransactionError::Malformed);
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+resolve,1,1,
+",
+            options,
+        )
+        .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 10.0);
+    }
+
+    #[test]
+    fn withdrawals_below_the_minimum_balance_should_fail() {
+        let options = LedgerOptions {
+            min_balance: Some(10.0),
+            ..LedgerOptions::default()
+        };
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,50
+withdrawal,1,2,40
+";
+
+        // Withdrawing down to the floor is allowed.
+        let ledger = create_test_ledger_with_options(transactions, options).unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 10.0);
+
+        let result = create_test_ledger_with_options(
+            &format!("{}withdrawal,1,3,0.01\n", transactions),
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::BelowMinimumBalance);
+
+        // Overdrawing is still reported as such.
+        let result = create_test_ledger_with_options(
+            &format!("{}withdrawal,1,3,20\n", transactions),
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::InsufficientFunds);
+    }
+
+    #[test]
+    fn amounts_over_the_maximum_should_fail() {
+        let options = LedgerOptions {
+            max_amount: Some(100.0),
+            ..LedgerOptions::default()
+        };
+
+        for (amount, result) in [
+            ("99.9999", Ok(())),
+            ("100", Ok(())),
+            ("100.0001", Err(TransactionError::AmountTooLarge)),
+        ] {
+            let deposit = create_test_ledger_with_options(
+                &format!("type,client,tx,amount\ndeposit,1,1,{}\n", amount),
+                options,
+            );
+            assert_eq!(deposit.map(|_| ()), result, "deposit of {}", amount);
+
+            let withdrawal = create_test_ledger_with_options(
+                &format!(
+                    "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,100\nwithdrawal,1,3,{}\n",
+                    amount
+                ),
+                options,
+            );
+            assert_eq!(withdrawal.map(|_| ()), result, "withdrawal of {}", amount);
+        }
+
+        let args = Args::parse_from(["toy-ledger", "--max-amount", "10000", "--amount-scale", "2"]);
+        assert_eq!(args.ledger_options().max_amount, Some(100.0));
+        assert!(Args::try_parse_from(["toy-ledger", "--max-amount", "0"]).is_err());
+    }
+
+    #[test]
+    fn minimum_balances_should_be_in_the_input_scale() {
+        let args = Args::parse_from(["toy-ledger", "--min-balance", "1000", "--amount-scale", "2"]);
+        assert_eq!(args.ledger_options().min_balance, Some(10.0));
+        assert!(Args::try_parse_from(["toy-ledger", "--min-balance", "-5"]).is_err());
+    }
+    #[test]
+    fn resolving_undisputed_transactions_should_fail_by_default() {
+        for row in ["resolve,1,1,", "resolve,1,9,", "chargeback,1,1,"] {
+            let result = create_test_ledger(&format!(
+                "\
+type,client,tx,amount
+deposit,1,1,10
+{}
+",
+                row
+            ));
+
+            assert!(matches!(
+                result.unwrap_err(),
+                TransactionError::NotDisputed | TransactionError::TransactionNotFound
+            ));
+        }
+    }
+
+    #[test]
+    fn resolving_undisputed_transactions_should_be_ignored_when_lenient() {
+        let options = LedgerOptions {
+            lenient_resolve: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+resolve,1,1,
+resolve,1,9,
+chargeback,1,1,
+chargeback,1,9,
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                available_funds: 10.0,
+                transaction_count: 1,
+                ..Account::new(1)
+            }
+        );
+
+        // Resolves of another client's transaction are still refused.
+        let result = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+resolve,2,1,
+",
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::Unauthorized);
+    }
+
+    #[test]
+    fn transactions_should_be_pruned_after_newer_ones_unless_disputed() {
+        let options = LedgerOptions {
+            prune_after: Some(2),
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,10
+dispute,1,2,
+deposit,1,3,10
+deposit,1,4,10
+deposit,1,5,10
+",
+            options,
+        )
+        .unwrap();
+
+        let mut tx_ids: Vec<u32> = ledger.transactions.keys().copied().collect();
+        tx_ids.sort_unstable();
+        assert_eq!(tx_ids, vec![2, 4, 5]);
+
+        let result = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,10
+deposit,1,3,10
+dispute,1,1,
+",
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::TransactionNotFound);
+    }
+
+    #[test]
+    fn pruned_ids_should_be_rejected_by_the_bloom_filter() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,10
+deposit,1,3,10
+deposit,1,1,10
+";
+        let mut rdr = csv::Reader::from_reader(transactions.as_bytes());
+        let transactions: Vec<Transaction> = rdr.deserialize().map(Result::unwrap).collect();
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options.prune_after = Some(1);
+        assert!(ledger.apply_all(transactions.clone()).is_empty());
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 40.0);
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options.prune_after = Some(1);
+        ledger.seen_tx_ids = Some(BloomFilter::new(100, 0.001));
+        assert_eq!(
+            ledger.apply_all(transactions),
+            vec![TransactionError::DuplicateTransactionID]
+        );
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 30.0);
+    }
+
+    #[test]
+    fn bloom_filters_should_never_miss_an_id() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+
+        for tx_id in 0..10_000 {
+            filter.insert(tx_id);
+        }
+
+        assert!((0..10_000).all(|tx_id| filter.contains(tx_id)));
+
+        // Well within the expected 1% of unseen IDs, allowing for chance.
+        let false_positives = (10_000..20_000)
+            .filter(|&tx_id| filter.contains(tx_id))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn verified_chargebacks_should_fail_when_held_funds_have_drifted() {
+        let options = LedgerOptions {
+            verify_chargebacks: true,
+            ..LedgerOptions::default()
+        };
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+";
+        let chargeback = Transaction {
+            tx_type: TransactionType::Chargeback,
+            client_id: 1,
+            tx_id: 1,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        let mut ledger = create_test_ledger_with_options(transactions, options).unwrap();
+        assert!(chargeback.append_to(&mut ledger).is_ok());
+
+        let mut ledger = create_test_ledger_with_options(transactions, options).unwrap();
+        ledger.accounts.get_mut(&1).unwrap().held_funds = 4.0;
+
+        assert_eq!(
+            chargeback.append_to(&mut ledger),
+            Err(TransactionError::UnbalancedChargeback {
+                held: -6.0,
+                released: 10.0,
+                disputed: 10.0,
+            })
+        );
+    }
+
+    #[test]
+    fn valid_chargeback_should_lock_account() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1
+deposit,1,3,2
+dispute,1,1,
+chargeback,1,1,
+",
+        )
+        .unwrap();
+
+        assert!(ledger.accounts.values().eq(vec![&Account {
+            client_id: 1,
+            available_funds: 2.0,
+            held_funds: 0.0,
+            status: AccountStatus::Locked,
+            shortfall: 0.0,
+            locked_by_tx: Some(1),
+            open_disputes: 0,
+            transaction_count: 2,
+        }]));
+    }
+
+    #[test]
+    fn disputes_of_non_matching_client_id_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1
+dispute,2,1,
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Unauthorized);
+    }
+
+    /// This test checks the case where a user spends and then
+    /// attempts to dispute their original deposit. The account
+    /// should be locked and further transactions prevented.
+    ///
+    /// deposits funds (tx#1)
+    /// purchases assets (tx#2)
+    /// withdraws funds (tx#3)
+    /// disputes deposit
+    /// resolve dispute
+    #[test]
+    fn prevent_malicious_actor() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+withdrawal,1,3,50
+dispute,1,1,
+chargeback,1,1,
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                held_funds: 0.0,
+                available_funds: -100.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn accounts_going_negative_should_be_frozen_when_enabled() {
+        let options = LedgerOptions {
+            freeze_on_negative: true,
+            ..LedgerOptions::default()
+        };
+
+        // The dispute of the spent deposit takes the account negative, so
+        // the account is frozen before the chargeback.
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+withdrawal,1,3,50
+dispute,1,1,
+",
+            options,
+        )
+        .unwrap();
+
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.available_funds, -100.0);
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert_eq!(account.locked_by_tx, Some(1));
+
+        // The open dispute can still be charged back, releasing its held
+        // funds, but nothing new is accepted.
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+withdrawal,1,3,50
+dispute,1,1,
+chargeback,1,1,
+",
+            options,
+        )
+        .unwrap();
+
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.available_funds, -100.0);
+        assert_eq!(account.held_funds, 0.0);
+        assert_eq!(account.status, AccountStatus::Locked);
+
+        let result = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,100
+deposit,1,3,10
+dispute,1,1,
+deposit,1,4,10
+",
+            options,
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::AccountLocked);
+
+        // Shortfalls count as going negative too.
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,100
+dispute,1,1,
+",
+            LedgerOptions {
+                track_shortfall: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert!(ledger.accounts.get(&1).unwrap().is_locked());
+    }
+
+    /// If an account is locked and then a dispute is made against a
+    /// transaction it has made the transaction should not be marked
+    /// as disputed.
+    #[test]
+    fn disputes_of_locked_accounts_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+dispute,1,2,
+chargeback,1,2,
+dispute,1,2,
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::AccountLocked);
+    }
+
+    #[test]
+    fn deposits_without_an_amount_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Malformed);
+    }
+
+    #[test]
+    fn withdrawals_without_an_amount_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+withdrawal,1,1,
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Malformed);
+    }
+
+    #[test]
+    fn process_rows_which_omit_final_comma() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,1
+resolve,1,1
+dispute,1,1
+chargeback,1,1
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                held_funds: 0.0,
+                available_funds: -90.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 2,
+                client_id: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn empty_amounts_should_read_as_no_amount() {
+        // Without trimming, so a blank field reaches the amount as is.
+        let read = |csv: &str| {
+            csv::ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(csv.as_bytes())
+                .deserialize::<Transaction>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let amounts = |transactions: Vec<Transaction>| -> Vec<Option<f64>> {
+            transactions.iter().map(|tx| tx.amount).collect()
+        };
+
+        let omitted = read("type,client,tx,amount\ndeposit,1,1,5\ndispute,1,1\n");
+        let empty = read("type,client,tx,amount\ndeposit,1,1,5\ndispute,1,1,\n");
+        let blank = read("type,client,tx,amount\ndeposit,1,1, 5 \ndispute,1,1,  \n");
+
+        assert_eq!(amounts(omitted), vec![Some(5.0), None]);
+        assert_eq!(amounts(empty), vec![Some(5.0), None]);
+        assert_eq!(amounts(blank), vec![Some(5.0), None]);
+
+        let json: Vec<_> = json_transactions(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":5}
+{"type":"dispute","client":1,"tx":1,"amount":null}
+{"type":"dispute","client":1,"tx":1}"#
+                .as_bytes(),
+            AmountFormat::default(),
+        )
+        .map(|tx| tx.unwrap().amount)
+        .collect();
+        assert_eq!(json, vec![Some(5.0), None, None]);
+
+        assert!(
+            csv::Reader::from_reader("type,client,tx,amount\ndeposit,1,1,five\n".as_bytes())
+                .deserialize::<Transaction>()
+                .next()
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn withdrawing_everything_left_should_survive_float_residue() {
+        // 0.3 - 0.1 is a hair under 0.2 as f64.
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.3
+withdrawal,1,2,0.1
+withdrawal,1,3,0.2
+",
+        )
+        .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 0.0);
+
+        // The residue grows with the balance, here to about 2e-9.
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,12345678.1
+deposit,1,2,0.2
+withdrawal,1,3,12345678.3
+",
+        )
+        .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 0.0);
+
+        let options = LedgerOptions {
+            min_balance: Some(0.0),
+            freeze_on_negative: true,
+            ..LedgerOptions::default()
+        };
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+withdrawal,1,3,0.3
+",
+            options,
+        )
+        .unwrap();
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.available_funds, 0.0);
+        assert!(!account.is_locked());
+
+        // More than residue is still refused.
+        let result = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.3
+withdrawal,1,2,0.300001
+",
+        );
+        assert_eq!(result.unwrap_err(), TransactionError::InsufficientFunds);
+    }
+
+    #[test]
+    fn withdrawing_more_than_available_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,120
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::InsufficientFunds);
+    }
+
+    /// This is counter-intuitive as the client doesn't have
+    /// any available funds to cover their held funds. Total
+    /// funds here does still reflect the true amount though.
+    #[test]
+    fn disputes_of_withdrawal_should_increase_held_funds_but_not_available_funds() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,2
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 10.0,
+                held_funds: 90.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 1,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn resolving_a_disputed_withdrawal_restores_balances() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,2
+resolve,1,2
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 100.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn chargeback_on_a_disputed_withdrawal_removes_held_funds() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,2
+chargeback,1,2
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 10.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(2),
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn second_transaction_with_duplicate_id_should_fail() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+
+        let err = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(90.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        }
+        .append_to(&mut ledger)
+        .unwrap_err();
+
+        // Rejects adding new transaction.
+        assert_eq!(err, TransactionError::DuplicateTransactionID);
+
+        // Maintains original transaction.
+        assert_eq!(
+            ledger.transactions.get(&1).unwrap(),
+            &Transaction {
+                tx_type: TransactionType::Deposit,
+                tx_id: 1,
+                client_id: 1,
+                amount: Some(100.0),
+                disputed: false,
+                settled: 100.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            }
+        );
+    }
+
+    /// Same scenario as `prevent_malicious_actor` but with shortfall
+    /// tracking enabled, available funds stay at zero and the deficit
+    /// is recorded separately.
+    #[test]
+    fn disputes_with_shortfall_tracking_clamp_available_funds() {
+        let options = LedgerOptions {
+            track_shortfall: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 0.0,
+                held_funds: 100.0,
+                status: AccountStatus::Active,
+                shortfall: 70.0,
+                locked_by_tx: None,
+                open_disputes: 1,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn resolving_with_shortfall_tracking_repays_shortfall() {
+        let options = LedgerOptions {
+            track_shortfall: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+resolve,1,1,
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 30.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn chargeback_with_shortfall_tracking_keeps_shortfall() {
+        let options = LedgerOptions {
+            track_shortfall: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+chargeback,1,1,
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 0.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 70.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    /// Spending between a resolve and a second dispute of the same
+    /// deposit drives available funds further negative, the chargeback
+    /// must then release exactly the amount held by the second dispute.
+    const REDISPUTED_DEPOSIT: &str = "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+resolve,1,1,
+withdrawal,1,3,20
+dispute,1,1,
+chargeback,1,1,
+";
+
+    #[test]
+    fn redisputed_deposits_should_chargeback_exactly() {
+        let ledger = create_test_ledger(REDISPUTED_DEPOSIT).unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: -90.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 3,
+            }
+        );
+        assert!(ledger.reconcile_holds().is_empty());
+        assert!(ledger.validate_consistency().is_empty());
+    }
+
+    #[test]
+    fn redisputed_deposits_with_shortfall_tracking_should_chargeback_exactly() {
+        let options = LedgerOptions {
+            track_shortfall: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(REDISPUTED_DEPOSIT, options).unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 0.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 90.0,
+                locked_by_tx: Some(1),
+                open_disputes: 0,
+                transaction_count: 3,
+            }
+        );
+        assert!(ledger.reconcile_holds().is_empty());
+        assert!(ledger.validate_consistency().is_empty());
+    }
+
+    #[test]
+    fn deposits_with_shortfall_tracking_pay_down_shortfall() {
+        let options = LedgerOptions {
+            track_shortfall: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+deposit,1,3,50
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 0.0,
+                held_funds: 100.0,
+                status: AccountStatus::Active,
+                shortfall: 20.0,
+                locked_by_tx: None,
+                open_disputes: 1,
+                transaction_count: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn uncovered_chargebacks_should_fail_when_rejected() {
+        let options = LedgerOptions {
+            reject_uncovered_chargeback: true,
+            ..LedgerOptions::default()
+        };
+
+        let err = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+withdrawal,1,3,50
+dispute,1,1,
+chargeback,1,1,
+",
+            options,
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::UncoveredChargeback);
+    }
+
+    #[test]
+    fn covered_chargebacks_should_succeed_when_uncovered_are_rejected() {
+        let options = LedgerOptions {
+            reject_uncovered_chargeback: true,
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+dispute,1,2,
+chargeback,1,2,
+",
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 100.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(2),
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn internal_transaction_ids_should_be_unique() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let first = ledger.next_internal_tx_id();
+        let second = ledger.next_internal_tx_id();
+
+        assert_eq!(first, Some(u32::MAX));
+        assert_eq!(second, Some(u32::MAX - 1));
+    }
+
+    #[test]
+    fn internal_transaction_ids_should_run_out_at_zero() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+dispute,1,1,
+chargeback,1,1,
+",
+        )
+        .unwrap();
+        ledger.internal_tx_id = Some(2);
+
+        assert_eq!(ledger.next_internal_tx_id(), Some(0));
+        assert_eq!(ledger.next_internal_tx_id(), None);
+        assert_eq!(ledger.next_internal_tx_id(), None);
+
+        // Sweeping without IDs to record it under moves no funds.
+        let before = ledger.accounts.clone();
+        assert_eq!(
+            ledger.sweep_locked(9),
+            Err(TransactionError::OutOfInternalIds)
+        );
+        assert_eq!(ledger.accounts, before);
+    }
+
+    #[test]
+    fn internal_transaction_ids_should_not_collide_with_recorded_ids() {
+        let mut ledger = create_test_ledger(&format!(
+            "\
+type,client,tx,amount
+deposit,1,{},100
+deposit,1,{},100
+",
+            u32::MAX,
+            u32::MAX - 2
+        ))
+        .unwrap();
+
+        let ids = [
+            ledger.next_internal_tx_id(),
+            ledger.next_internal_tx_id(),
+            ledger.next_internal_tx_id(),
+        ];
+
+        assert_eq!(
+            ids,
+            [Some(u32::MAX - 1), Some(u32::MAX - 3), Some(u32::MAX - 4)]
+        );
+        assert!(ids
+            .iter()
+            .all(|id| !ledger.transactions.contains_key(&id.unwrap())));
+    }
+
+    #[test]
+    fn internal_transactions_should_not_overwrite_partner_ids() {
+        let mut ledger = create_test_ledger(&format!(
+            "\
+type,client,tx,amount
+deposit,1,{0},100
+deposit,1,1,50
+dispute,1,{0},
+",
+            u32::MAX
+        ))
+        .unwrap();
+
+        // The fee and the sweep skip the partner's ID at the top.
+        ledger.charge_held_fee(0.1).unwrap();
+        ledger
+            .process(format!("type,client,tx,amount\nchargeback,1,{},\n", u32::MAX).as_bytes())
+            .unwrap();
+        ledger.sweep_locked(9).unwrap();
+
+        let partner = &ledger.transactions[&u32::MAX];
+        assert_eq!(
+            (partner.tx_type, partner.client_id, partner.amount),
+            (TransactionType::Deposit, 1, Some(100.0))
+        );
+        assert_eq!(ledger.transactions[&(u32::MAX - 1)].amount, Some(10.0));
+        assert_eq!(ledger.transactions[&(u32::MAX - 2)].client_id, 1);
+        assert_eq!(ledger.transactions[&(u32::MAX - 3)].client_id, 9);
+        assert_eq!(ledger.accounts[&9].available_funds, 40.0);
+
+        // A partner reusing an internal ID is refused like any duplicate.
+        let err = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: u32::MAX - 2,
+            client_id: 2,
+            amount: Some(5.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        }
+        .append_to(&mut ledger)
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::DuplicateTransactionID);
+        assert_eq!(ledger.transactions[&(u32::MAX - 2)].client_id, 1);
+        assert!(!ledger.accounts.contains_key(&2));
+    }
+
+    #[test]
+    fn serialized_accounts_should_include_computed_total() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,40
+dispute,1,1,
+",
+        )
+        .unwrap();
+
+        let mut output = vec![];
+        output::write_csv(
+            ledger.accounts.values(),
+            &mut output,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(
+            output,
+            "\
+client,available,held,total,locked
+1,-40.0,100.0,60.0,false
+"
+        );
+    }
+
+    #[test]
+    fn json_output_should_be_compact_by_default() {
+        let account = Account {
+            client_id: 1,
+            available_funds: 1.5,
+            held_funds: 0.0,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+        };
+
+        let mut json = vec![];
+        output::write_json([&account], &mut json, false).unwrap();
+
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"client\":1,\"available\":1.5,\"held\":0.0,\"total\":1.5,\"locked\":false}]\n"
+        );
+    }
+
+    #[test]
+    fn json_output_should_be_indented_when_pretty() {
+        let account = Account {
+            client_id: 1,
+            available_funds: 1.5,
+            held_funds: 0.0,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+        };
+
+        let mut json = vec![];
+        output::write_json([&account], &mut json, true).unwrap();
+
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "\
+[
+  {
+    \"client\": 1,
+    \"available\": 1.5,
+    \"held\": 0.0,
+    \"total\": 1.5,
+    \"locked\": false
+  }
+]
+"
+        );
+    }
+
+    #[test]
+    fn config_files_should_parse_flag_names() {
+        let config: Config = toml::from_str(
+            "\
+format = \"json\"
+pretty = true
+track-shortfall = true
+precision = 4
+rounding = \"half-even\"
+delimiter = \";\"
+amount-scale = 2
+log-rejected = true
+error-format = \"json\"
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                format: Some(OutputFormat::Json),
+                pretty: true,
+                track_shortfall: true,
+                reject_uncovered_chargeback: false,
+                precision: Some(4),
+                rounding: Some(RoundingMode::HalfEven),
+                delimiter: Some(';'),
+                amount_scale: Some(2),
+                log_rejected: true,
+                error_format: Some(ErrorFormat::Json),
+                allow_deposit_when_locked: false,
+                max_open_disputes: None,
+                lenient_resolve: false,
+                prune_after: None,
+                verify_chargebacks: false,
+                max_tx_per_client: None,
+                min_balance: None,
+                max_amount: None,
+                freeze_on_negative: false,
+                strict_dispute_amounts: false,
+                allow_close: false,
+            }
+        );
+    }
+
+    #[test]
+    fn config_files_with_unknown_keys_should_fail() {
+        assert!(toml::from_str::<Config>("colour = \"blue\"").is_err());
+    }
+
+    #[test]
+    fn command_line_flags_should_override_config() {
+        let mut args = Args::parse_from(["toy-ledger", "input.csv", "--format", "csv"]);
+
+        args.apply_config(Config {
+            format: Some(OutputFormat::Json),
+            pretty: true,
+            ..Config::default()
+        });
+
+        assert_eq!(args.format, Some(OutputFormat::Csv));
+        assert!(args.pretty);
+        assert!(!args.track_shortfall);
+    }
+
+    #[test]
+    fn negated_flags_should_override_config() {
+        let mut args =
+            Args::parse_from(["toy-ledger", "input.csv", "--no-pretty", "--allow-close"]);
+
+        args.apply_config(Config {
+            pretty: true,
+            track_shortfall: true,
+            ..Config::default()
+        });
+
+        assert!(!args.pretty);
+        assert!(args.track_shortfall);
+        assert!(args.allow_close);
+
+        // The last of a flag and its negation wins.
+        let args = Args::parse_from(["toy-ledger", "input.csv", "--no-pretty", "--pretty"]);
+        assert!(args.pretty && !args.no_pretty);
+    }
+
+    #[test]
+    fn input_and_rounding_options_should_default_from_config() {
+        let config = || Config {
+            precision: Some(4),
+            rounding: Some(RoundingMode::HalfEven),
+            delimiter: Some(';'),
+            amount_scale: Some(2),
+            log_rejected: true,
+            error_format: Some(ErrorFormat::Json),
+            ..Config::default()
+        };
+
+        let mut args = Args::parse_from(["toy-ledger", "input.csv"]);
+        args.apply_config(config());
+        assert_eq!(args.ledger_options().precision, Some(4));
+        assert_eq!(args.ledger_options().rounding, Rounding::HalfEven);
+        assert_eq!(args.delimiter(), b';');
+        assert_eq!(args.amount_format().scale, 2);
+        assert!(args.log_rejected);
+        assert_eq!(args.error_format, Some(ErrorFormat::Json));
+
+        let mut args = Args::parse_from([
+            "toy-ledger",
+            "input.csv",
+            "--precision",
+            "2",
+            "--rounding",
+            "toward-zero",
+            "--delimiter",
+            "|",
+            "--amount-scale",
+            "0",
+            "--no-log-rejected",
+            "--error-format",
+            "text",
+        ]);
+        args.apply_config(config());
+        assert_eq!(args.ledger_options().precision, Some(2));
+        assert_eq!(args.ledger_options().rounding, Rounding::TowardZero);
+        assert_eq!(args.delimiter(), b'|');
+        assert_eq!(args.amount_format().scale, 0);
+        assert!(!args.log_rejected);
+        assert_eq!(args.error_format, Some(ErrorFormat::Text));
+
+        // Without either the defaults are used.
+        let args = Args::parse_from(["toy-ledger", "input.csv"]);
+        assert_eq!(args.ledger_options().rounding, Rounding::HalfAwayFromZero);
+        assert_eq!(args.delimiter(), b',');
+        assert_eq!(args.amount_format().scale, 0);
+    }
+
+    #[test]
+    fn funds_should_round_in_each_mode() {
+        let round = |funds: f64, rounding: Rounding| {
+            let mut account = Account {
+                available_funds: funds,
+                ..Account::new(1)
+            };
+            account.round_funds(2, rounding);
+            account.available_funds
+        };
+
+        assert_eq!(round(0.125, Rounding::HalfAwayFromZero), 0.13);
+        assert_eq!(round(-0.125, Rounding::HalfAwayFromZero), -0.13);
+        assert_eq!(round(0.125, Rounding::HalfEven), 0.12);
+        assert_eq!(round(0.375, Rounding::HalfEven), 0.38);
+        assert_eq!(round(0.129, Rounding::TowardZero), 0.12);
+        assert_eq!(round(-0.129, Rounding::TowardZero), -0.12);
+    }
+
+    #[test]
+    fn merging_disjoint_ledgers_should_combine_accounts() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+
+        let other = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,2,2,50
+dispute,2,2,
+",
+        )
+        .unwrap();
+
+        ledger.merge(other).unwrap();
+
+        assert_eq!(ledger.transactions.len(), 2);
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 100.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 1,
+            }
+        );
+        assert_eq!(
+            ledger.accounts.get(&2).unwrap(),
+            &Account {
+                client_id: 2,
+                available_funds: 0.0,
+                held_funds: 50.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 1,
+                transaction_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn merging_overlapping_accounts_should_sum_funds_and_keep_locks() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+
+        let other = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,2,50
+deposit,1,3,20
+dispute,1,3,
+chargeback,1,3,
+",
+        )
+        .unwrap();
+
+        ledger.merge(other).unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 150.0,
+                held_funds: 0.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(3),
+                open_disputes: 0,
+                transaction_count: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn merging_overlapping_transaction_ids_should_fail() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+
+        let other = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,2,2,50
+deposit,2,1,50
+",
+        )
+        .unwrap();
+
+        let err = ledger.merge(other).unwrap_err();
+
+        assert_eq!(err, TransactionError::DuplicateTransactionID);
+
+        // Leaves the ledger untouched.
+        assert_eq!(ledger.transactions.len(), 1);
+        assert!(!ledger.accounts.contains_key(&2));
+    }
+
+    #[test]
+    fn deposits_of_nan_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,NaN
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Malformed);
+    }
+
+    #[test]
+    fn deposits_of_infinity_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,inf
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Malformed);
+    }
+
+    #[test]
+    fn withdrawals_of_negative_infinity_should_fail() {
+        let err = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,-inf
+",
+        )
+        .unwrap_err();
+
+        assert_eq!(err, TransactionError::Malformed);
+    }
+
+    #[test]
+    fn processing_should_skip_transactions_which_fail() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        ledger
+            .process(
+                "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,120
+dispute,1,5,
+withdrawal,1,3,20
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 80.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn processing_should_stop_after_limit() {
+        let args = Args::parse_from(["toy-ledger", "input.csv", "--limit", "2"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let summary = process(
+            &args,
+            &mut ledger,
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,120
+deposit,1,3,50
+"
+            .as_bytes(),
+            args.limit.unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(summary.transactions, 2);
+        assert_eq!(ledger.transactions.len(), 2);
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 100.0);
+    }
+
+    #[test]
+    fn account_balances_should_add_up_within_epsilon() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+",
+        )
+        .unwrap();
+
+        assert_account_approx_eq(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 0.3,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 0,
+            },
+            1e-9,
+        );
+    }
+
+    #[test]
+    fn negative_held_funds_should_be_caught() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+dispute,1,1,
+dispute,1,2,
+resolve,1,1,
+resolve,1,2,
+deposit,2,3,50
+deposit,3,4,20
+",
+        )
+        .unwrap();
+
+        assert!(negative_held_clients(&ledger).is_empty());
+
+        // As a chargeback releasing more than was held would leave it.
+        ledger.accounts.get_mut(&2).unwrap().held_funds = -10.0;
+        assert_eq!(negative_held_clients(&ledger), vec![2]);
+    }
+
+    #[test]
+    fn split_output_should_write_locked_accounts_separately() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,50
+deposit,3,3,20
+dispute,2,2,
+chargeback,2,2,
+",
+        )
+        .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("toy-ledger-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (active, locked) = (dir.join("active.csv"), dir.join("locked.csv"));
+
+        let args = Args::parse_from([
+            "toy-ledger".as_ref(),
+            "--split-output".as_ref(),
+            active.as_os_str(),
+            locked.as_os_str(),
+        ]);
+        write_accounts(&args, &ledger, &HashMap::new());
+        let written = (
+            std::fs::read_to_string(&active).unwrap(),
+            std::fs::read_to_string(&locked).unwrap(),
+        );
+
+        // Omitting the locked path drops the locked accounts.
+        std::fs::remove_file(&locked).unwrap();
+        let args = Args::parse_from([
+            "toy-ledger".as_ref(),
+            "--split-output".as_ref(),
+            locked.as_os_str(),
+        ]);
+        write_accounts(&args, &ledger, &HashMap::new());
+        let only_active = std::fs::read_to_string(&locked).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            written.0,
+            "\
+client,available,held,total,locked
+1,100.0,0.0,100.0,false
+3,20.0,0.0,20.0,false
+"
+        );
+        assert_eq!(
+            written.1,
+            "\
+client,available,held,total,locked
+2,0.0,0.0,0.0,true
+"
+        );
+        assert_eq!(only_active, written.0);
+    }
+
+    #[test]
+    fn accounts_read_back_should_approx_eq_those_written() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+deposit,2,3,10
+dispute,2,3,
+chargeback,2,3,
+",
+        )
+        .unwrap();
+
+        let mut written = vec![];
+        output::write_csv(
+            ledger.export_accounts(),
+            &mut written,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        let read = read_accounts(written.as_slice(), CheckAction::Error).unwrap();
+
+        for (id, account) in &ledger.accounts {
+            assert!(account.approx_eq(&read[id], 1e-9));
+        }
+
+        let mut drifted = read[&1].clone();
+        drifted.available_funds += 1e-6;
+        assert!(!drifted.approx_eq(&ledger.accounts[&1], 1e-9));
+        assert!(!read[&1].approx_eq(&read[&2], 1e-9));
+    }
+
+    #[test]
+    fn diff_should_report_accounts_outside_epsilon() {
+        let left = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.1
+deposit,1,2,0.2
+deposit,2,3,5
+deposit,3,4,1
+",
+        )
+        .unwrap();
+
+        let right = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,0.3
+deposit,2,3,5.1
+deposit,4,4,1
+",
+        )
+        .unwrap();
+
+        assert_eq!(left.diff(&right, 1e-9), vec![2, 3, 4]);
+        assert_eq!(left.diff(&right, 0.2), vec![3, 4]);
+        assert!(left.diff(&left, 0.0).is_empty());
+    }
+
+    #[test]
+    fn cloned_ledgers_should_not_share_state() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+",
+        )
+        .unwrap();
+
+        let mut what_if = ledger.clone();
+        Transaction {
+            tx_type: TransactionType::Dispute,
+            tx_id: 1,
+            client_id: 1,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        }
+        .append_to(&mut what_if)
+        .unwrap();
+
+        assert_eq!(what_if.diff(&ledger, 0.0), vec![1]);
+        assert_eq!(ledger.is_disputed(1), Some(false));
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 10.0);
+    }
+
+    #[test]
+    fn processing_an_empty_file_should_read_no_transactions() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let args = Args::parse_from(["toy-ledger"]);
+        let summary = process(&args, &mut ledger, "".as_bytes(), usize::MAX).unwrap();
+
+        assert_eq!(summary.transactions, 0);
+        assert!(ledger.accounts.is_empty());
+    }
+
+    #[test]
+    fn processing_a_header_only_file_should_read_no_transactions() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let args = Args::parse_from(["toy-ledger"]);
+        let summary = process(
+            &args,
+            &mut ledger,
+            "type,client,tx,amount\n".as_bytes(),
+            usize::MAX,
+        )
+        .unwrap();
+
+        assert_eq!(summary.transactions, 0);
+        assert!(ledger.accounts.is_empty());
+    }
+
+    #[test]
+    fn dispute_state_should_be_queryable() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+deposit,1,3,20
+dispute,1,1,
+dispute,1,2,
+resolve,1,2,
+",
+        )
+        .unwrap();
+
+        assert_eq!(ledger.is_disputed(1), Some(true));
+        assert_eq!(ledger.is_disputed(2), Some(false));
+        assert_eq!(ledger.is_disputed(3), Some(false));
+        assert_eq!(ledger.is_disputed(4), None);
+    }
+
+    #[test]
+    fn held_funds_should_reconcile_with_disputes() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,30
+deposit,2,3,0.1
+deposit,2,4,0.2
+dispute,1,1,
+dispute,1,2,
+dispute,2,3,
+dispute,2,4,
+resolve,2,3,
+",
+        )
+        .unwrap();
+
+        assert!(ledger.reconcile_holds().is_empty());
+    }
+
+    #[test]
+    fn held_funds_without_disputes_should_not_reconcile() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,100
+deposit,3,3,100
+dispute,3,3,
+",
+        )
+        .unwrap();
+
+        ledger.accounts.get_mut(&2).unwrap().held_funds = 10.0;
+        ledger.accounts.get_mut(&3).unwrap().held_funds = 90.0;
+
+        assert_eq!(ledger.reconcile_holds(), vec![2, 3]);
+    }
+
+    #[test]
+    fn balances_should_be_consistent_with_recorded_transactions() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,30
+dispute,1,2,
+resolve,1,2,
+deposit,2,3,50
+deposit,2,4,20
+dispute,2,3,
+chargeback,2,3,
+deposit,3,5,10
+dispute,3,5,
+",
+        )
+        .unwrap();
+
+        let overdraft = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id: 6,
+            amount: Some(500.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        assert_eq!(
+            overdraft.append_to(&mut ledger).unwrap_err(),
+            TransactionError::InsufficientFunds
+        );
+
+        ledger.charge_held_fee(0.01).unwrap();
+
+        assert!(ledger.validate_consistency().is_empty());
+    }
+
+    #[test]
+    fn drifted_balances_should_not_be_consistent() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,100
+deposit,3,3,100
+deposit,4,4,100
+dispute,3,3,
+",
+        )
+        .unwrap();
+
+        ledger.accounts.get_mut(&1).unwrap().available_funds = 90.0;
+        ledger.accounts.remove(&2);
+        ledger.accounts.get_mut(&3).unwrap().held_funds = 0.0;
+
+        assert_eq!(ledger.validate_consistency(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn changed_accounts_should_be_taken_once() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+        assert!(ledger.take_changed().is_empty());
+
+        ledger.track_changes();
+
+        let transactions = [
+            (TransactionType::Deposit, 3, 2, Some(10.0)),
+            (TransactionType::Withdrawal, 2, 3, Some(10.0)),
+            (TransactionType::Dispute, 1, 1, None),
+        ];
+        for (tx_type, client_id, tx_id, amount) in transactions {
+            let _ = Transaction {
+                tx_type,
+                client_id,
+                tx_id,
+                amount,
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            }
+            .append_to(&mut ledger);
+        }
+
+        // The withdrawal was rejected so client 2's account didn't change.
+        assert_eq!(ledger.take_changed(), vec![1, 3]);
+        assert!(ledger.take_changed().is_empty());
+
+        ledger.charge_held_fee(0.1).unwrap();
+        assert_eq!(ledger.take_changed(), vec![1]);
+    }
+
+    #[test]
+    fn swept_locked_accounts_should_move_funds_to_the_sweep_account() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,40
+dispute,1,2,
+chargeback,1,2,
+deposit,2,3,25
+deposit,9,4,5
+",
+        )
+        .unwrap();
+
+        ledger.sweep_locked(9).unwrap();
+
+        let locked = ledger.accounts.get(&1).unwrap();
+        assert_eq!(locked.available_funds, 0.0);
+        assert_eq!(locked.status, AccountStatus::Locked);
+        assert_eq!(ledger.accounts.get(&2).unwrap().available_funds, 25.0);
+        assert_eq!(ledger.accounts.get(&9).unwrap().available_funds, 105.0);
+
+        // The sweep is recorded as a withdrawal and a matching deposit.
+        let swept: Vec<_> = output::audit_records(&ledger)
+            .into_iter()
+            .filter(|record| record.tx > 4)
+            .map(|record| (record.tx_type, record.client, record.amount))
+            .collect();
+        assert_eq!(swept.len(), 2);
+        assert!(swept.contains(&("withdrawal", 1, Some(100.0))));
+        assert!(swept.contains(&("deposit", 9, Some(100.0))));
+    }
+
+    #[test]
+    fn transactions_from_unregistered_clients_should_fail() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+",
+        )
+        .unwrap();
+        ledger.registered_clients = Some(BTreeSet::from([1]));
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 2,
+            tx_id: 2,
+            amount: Some(50.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            amount: None,
+            ..deposit.clone()
+        };
+
+        assert_eq!(
+            deposit.append_to(&mut ledger).unwrap_err(),
+            TransactionError::UnknownClient
+        );
+        assert_eq!(
+            dispute.append_to(&mut ledger).unwrap_err(),
+            TransactionError::UnknownClient
+        );
+        assert!(!ledger.accounts.contains_key(&2));
+        assert_eq!(ledger.is_disputed(2), None);
+        assert!(ledger.validate_consistency().is_empty());
+    }
+
+    #[test]
+    fn registered_clients_should_be_read_one_per_line() {
+        let clients = read_client_ids("1\n7\n\n  42\n".as_bytes()).unwrap();
+        assert_eq!(clients, BTreeSet::from([1, 7, 42]));
+
+        let err = read_client_ids("1\nseven\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("\"seven\""));
+    }
+
+    #[test]
+    fn csv_output_should_quote_all_fields_when_always() {
+        let account = Account {
+            client_id: 1,
+            available_funds: 1.5,
+            held_funds: 0.0,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+        };
+
+        let mut output = vec![];
+        output::write_csv([&account], &mut output, csv::QuoteStyle::Always).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+\"client\",\"available\",\"held\",\"total\",\"locked\"
+\"1\",\"1.5\",\"0.0\",\"1.5\",\"false\"
+"
+        );
+    }
+
+    #[test]
+    fn table_output_should_align_columns() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,1.5
+deposit,12,2,1000
+dispute,12,2,
+chargeback,12,2,
+",
+        )
+        .unwrap();
+
+        let mut output = vec![];
+        output::write_table(ledger.export_accounts(), &mut output, Some(2)).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client  available  held  total  locked
+------  ---------  ----  -----  ------
+     1       1.50  0.00   1.50  false
+    12       0.00  0.00   0.00  true
+"
+        );
+
+        let args = Args::parse_from(["toy-ledger", "--format", "table"]);
+        assert_eq!(args.format, Some(OutputFormat::Table));
+    }
+
+    #[test]
+    fn output_columns_should_select_and_order_account_fields() {
+        let account = Account {
+            client_id: 1,
+            available_funds: 1.5,
+            held_funds: 2.0,
+            status: AccountStatus::Active,
+            shortfall: 0.0,
+            locked_by_tx: None,
+            open_disputes: 0,
+            transaction_count: 0,
+        };
+
+        let args = Args::parse_from(["toy-ledger", "--output-columns", "total,client"]);
+        let columns = args.output_columns.unwrap();
+
+        let mut output = vec![];
+        output::write_csv(
+            [AccountColumns {
+                account: &account,
+                columns: &columns,
+            }],
+            &mut output,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "total,client\n3.5,1\n");
+
+        // Every column in order matches the default output.
+        assert_eq!(
+            serde_json::to_string(&AccountColumns {
+                account: &account,
+                columns: &AccountColumn::ALL,
+            })
+            .unwrap(),
+            serde_json::to_string(&account).unwrap()
+        );
+
+        assert!(
+            Args::try_parse_from(["toy-ledger", "--output-columns", "client,balance"]).is_err()
+        );
+    }
+
+    #[test]
+    fn applying_transactions_should_collect_errors() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let errors = ledger.apply_all([
+            Transaction {
+                tx_type: TransactionType::Deposit,
+                tx_id: 1,
+                client_id: 1,
+                amount: Some(100.0),
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+            Transaction {
+                tx_type: TransactionType::Withdrawal,
+                tx_id: 2,
+                client_id: 1,
+                amount: Some(120.0),
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+            Transaction {
+                tx_type: TransactionType::Dispute,
+                tx_id: 3,
+                client_id: 1,
+                amount: None,
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+            Transaction {
+                tx_type: TransactionType::Withdrawal,
+                tx_id: 4,
+                client_id: 1,
+                amount: Some(40.0),
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+        ]);
+
+        assert_eq!(
+            errors,
+            vec![
+                TransactionError::InsufficientFunds,
+                TransactionError::TransactionNotFound
+            ]
+        );
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 60.0);
+    }
+
+    #[test]
+    fn lenient_processing_should_report_errors_with_rows_in_input_order() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let errors = ledger
+            .process_lenient(
+                "\
+type,client,tx,amount
+deposit,1,1,100.0
+withdrawal,1,2,120.0
+deposit,2,3,50.0
+dispute,1,9,
+withdrawal,2,4,20.0
+deposit,1,1,10.0
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            errors,
+            vec![
+                (3, TransactionError::InsufficientFunds),
+                (5, TransactionError::TransactionNotFound),
+                (7, TransactionError::DuplicateTransactionID),
+            ]
+        );
+        assert_eq!(ledger.accounts.get(&2).unwrap().available_funds, 30.0);
+    }
+
+    #[test]
+    fn rejected_transactions_should_be_logged_as_json_lines() {
+        let transaction = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            tx_id: 2,
+            client_id: 1,
+            amount: Some(120.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        assert_eq!(
+            rejected_json(
+                &transaction,
+                &TransactionError::InsufficientFunds,
+                InputPosition {
+                    row: 3,
+                    byte: Some(36)
+                }
+            ),
+            r#"{"tx":2,"client":1,"type":"withdrawal","error":"insufficient_funds","row":3,"byte":36}"#
+        );
+        assert_eq!(
+            TransactionError::Rejected {
+                reason: "withdrawal over 100".to_string()
+            }
+            .name(),
+            "rejected"
+        );
+
+        let args = Args::parse_from(["toy-ledger", "--error-format", "json"]);
+        assert_eq!(args.error_format, Some(ErrorFormat::Json));
+    }
+
+    #[test]
+    fn transactions_should_be_read_with_their_row_and_byte_offset() {
+        let input = "#schema=1\ntype,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,9\n";
+
+        let positions: Vec<_> = Args::parse_from(["toy-ledger"])
+            .transactions(input.as_bytes())
+            .unwrap()
+            .map(|transaction| transaction.unwrap().1)
+            .collect();
+
+        assert_eq!(
+            positions,
+            vec![
+                InputPosition {
+                    row: 2,
+                    byte: Some(32)
+                },
+                InputPosition {
+                    row: 3,
+                    byte: Some(46)
+                },
+            ]
+        );
+        assert_eq!(&input[46..], "withdrawal,1,2,9\n");
+
+        let positions: Vec<_> = Args::parse_from(["toy-ledger", "--input-format", "json"])
+            .transactions(r#"{"type":"deposit","client":1,"tx":1,"amount":5}"#.as_bytes())
+            .unwrap()
+            .map(|transaction| transaction.unwrap().1)
+            .collect();
+        assert_eq!(positions, vec![InputPosition { row: 1, byte: None }]);
+    }
+
+    #[test]
+    fn processing_with_a_report_should_count_and_collect_errors() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let report = ledger
+            .process_with_report(
+                "\
+type,client,tx,amount
+deposit,1,1,100.0
+withdrawal,1,2,120.0
+deposit,2,3,50.0
+dispute,1,9,
+dispute,2,3,
+resolve,2,3,
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        assert_eq!(report.read, 6);
+        assert_eq!(report.applied, 4);
+        assert_eq!(report.count(TransactionType::Deposit), 2);
+        assert_eq!(report.count(TransactionType::Dispute), 2);
+        assert_eq!(report.count(TransactionType::Chargeback), 0);
+        assert_eq!(
+            report.failures,
+            vec![
+                (3, TransactionError::InsufficientFunds),
+                (5, TransactionError::TransactionNotFound),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_should_be_queryable_on_recorded_transactions() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount,memo
+deposit,1,1,100,\"refund, order 42\"
+deposit,1,2,50,
+dispute,1,1,,disputed by phone
+",
+        )
+        .unwrap();
+
+        assert_eq!(ledger.comment(1), Some("refund, order 42"));
+        assert_eq!(ledger.comment(2), None);
+        assert_eq!(ledger.comment(3), None);
+        assert_eq!(ledger.accounts.get(&1).unwrap().held_funds, 100.0);
+    }
+
+    #[test]
+    fn json_input_should_keep_extra_fields_as_metadata() {
+        let input = r#"
+{"type": "deposit", "client": 1, "tx": 1, "amount": 100, "channel": "web", "risk": {"score": 3}}
+{"type": "dispute", "client": 1, "tx": 1, "memo": "by phone"}
+"#;
+        let transactions = json_transactions(input.as_bytes(), AmountFormat::default())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].amount, Some(100.0));
+        assert_eq!(
+            serde_json::Value::Object(transactions[0].metadata.clone()),
+            serde_json::json!({"channel": "web", "risk": {"score": 3}})
+        );
+        assert_eq!(transactions[1].comment.as_deref(), Some("by phone"));
+        assert!(transactions[1].metadata.is_empty());
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        for transaction in &transactions {
+            transaction.append_to(&mut ledger).unwrap();
+        }
+
+        let mut json = vec![];
+        output::write_json(output::audit_records(&ledger), &mut json, false).unwrap();
+
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":100.0,\"disputed\":true,\
+             \"channel\":\"web\",\"risk\":{\"score\":3}}]\n"
+        );
+    }
+
+    #[test]
+    fn csv_input_should_have_no_metadata() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount,channel
+deposit,1,1,100,web
+",
+        )
+        .unwrap();
+
+        assert!(ledger.transactions.get(&1).unwrap().metadata.is_empty());
+    }
+
+    #[test]
+    fn dates_should_parse_from_dates_and_timestamps() {
+        let date = Date {
+            year: 2022,
+            month: 9,
+            day: 1,
+        };
+
+        assert_eq!("2022-09-01".parse(), Ok(date));
+        assert_eq!("2022-09-01T23:59:59+10:00".parse(), Ok(date));
+        assert_eq!(date.to_string(), "2022-09-01");
+        assert_eq!(
+            "2022-13-01".parse::<Date>(),
+            Err(TransactionError::Malformed)
+        );
+        assert_eq!(
+            "01/09/2022".parse::<Date>(),
+            Err(TransactionError::Malformed)
+        );
+    }
+
+    #[test]
+    fn daily_snapshots_should_reflect_state_at_end_of_each_date() {
+        let dir = std::env::temp_dir().join(format!("toy-ledger-daily-{}", std::process::id()));
+        let args = Args::parse_from(["toy-ledger", "input.csv"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let summary = process_daily(
+            &args,
+            &mut ledger,
+            "\
+type,client,tx,amount,date
+deposit,1,1,100,2022-09-01
+deposit,1,3,5,2022-09-02
+withdrawal,1,2,30,2022-09-01
+dispute,1,1,,2022-09-02
+"
+            .as_bytes(),
+            usize::MAX,
+            &dir,
+        )
+        .unwrap();
+
+        let first_day = std::fs::read_to_string(dir.join("2022-09-01.csv")).unwrap();
+        let second_day = std::fs::read_to_string(dir.join("2022-09-02.csv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(summary.transactions, 4);
+        assert_eq!(
+            first_day,
+            "\
+client,available,held,total,locked
+1,70.0,0.0,70.0,false
+"
+        );
+        assert_eq!(
+            second_day,
+            "\
+client,available,held,total,locked
+1,-25.0,100.0,75.0,false
+"
+        );
+    }
+
+    #[test]
+    fn input_path_should_fall_back_to_environment() {
+        std::env::set_var("LEDGER_INPUT", "from-env.csv");
+
+        let from_env = Args::parse_from(["toy-ledger"]);
+        let from_args = Args::parse_from(["toy-ledger", "from-args.csv"]);
+
+        std::env::remove_var("LEDGER_INPUT");
+
+        assert_eq!(from_env.csv_filenames, vec![PathBuf::from("from-env.csv")]);
+        assert_eq!(
+            from_args.csv_filenames,
+            vec![PathBuf::from("from-args.csv")]
+        );
+    }
+
+    #[test]
+    fn explaining_should_trace_a_transaction_and_its_disputes() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let traces: Vec<String> = TransactionReader::new(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+dispute,1,1,
+withdrawal,1,3,100
+resolve,1,1,
+"
+            .as_bytes(),
+            AmountFormat::default(),
+        )
+        .unwrap()
+        .filter_map(|transaction| {
+            apply_explained(
+                &transaction.unwrap(),
+                &mut ledger,
+                Some(1),
+                AmountFormat::default(),
+                None,
+            )
+            .1
+        })
+        .collect();
+
+        assert_eq!(
+            traces,
+            vec![
+                "tx 1 deposit by client 1: applied\n  available: 0.0 -> 100.0\n  held: 0.0 -> 0.0\n  total: 0.0 -> 100.0\n",
+                "tx 1 dispute by client 1: applied\n  available: 150.0 -> 50.0\n  held: 0.0 -> 100.0\n  total: 150.0 -> 150.0\n",
+                "tx 1 resolve by client 1: applied\n  available: 50.0 -> 150.0\n  held: 100.0 -> 0.0\n  total: 150.0 -> 150.0\n",
+            ]
+        );
+
+        // Funds are traced as they're output, in cents and to --precision.
+        let format = AmountFormat {
+            scale: 2,
+            ..AmountFormat::default()
+        };
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let deposit = TransactionReader::new(
+            "type,client,tx,amount\ndeposit,1,1,12345\n".as_bytes(),
+            format,
+        )
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+        let (_, trace) = apply_explained(&deposit, &mut ledger, Some(1), format, Some(2));
+        assert_eq!(
+            trace.unwrap(),
+            "tx 1 deposit by client 1: applied\n  available: 0.00 -> 12345.00\n  held: 0.00 -> 0.00\n  total: 0.00 -> 12345.00\n"
+        );
+    }
+
+    #[test]
+    fn comparing_should_report_clients_differing_from_expected_output() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,50
+deposit,3,3,20
+dispute,3,3,
+chargeback,3,3,
+",
+        )
+        .unwrap();
+
+        let expected = read_accounts(
+            "\
+client,available,held,total,locked
+1,100.0,0.0,100.0,false
+2,40.0,0.0,40.0,false
+3,0.0,0.0,0.0,true
+4,10.0,0.0,10.0,false
+"
+            .as_bytes(),
+            CheckAction::Error,
+        )
+        .unwrap();
+
+        assert_eq!(
+            compare_accounts(expected, ledger.accounts.values().cloned().collect()),
+            vec![
+                "client 2: expected available 40.0, held 0.0, total 40.0, locked false, got available 50.0, held 0.0, total 50.0, locked false",
+                "client 4: missing from the computed accounts",
+            ]
+        );
+    }
+
+    #[test]
+    fn comparing_should_accept_the_tools_own_shortfall_output() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,100
+dispute,1,1,
+";
+        let args = Args::parse_from(["toy-ledger", "--track-shortfall"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options = args.ledger_options();
+        process(&args, &mut ledger, transactions.as_bytes(), usize::MAX).unwrap();
+
+        let mut written = vec![];
+        output::write_csv(
+            scaled_accounts(&args, &ledger),
+            &mut written,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(written.clone()).unwrap(),
+            "client,available,held,total,locked\n1,0.0,100.0,0.0,false\n"
+        );
+
+        let expected = read_accounts(written.as_slice(), CheckAction::Error).unwrap();
+        assert!(compare_accounts(expected, scaled_accounts(&args, &ledger)).is_empty());
+    }
+
+    #[test]
+    fn snapshots_should_verify_against_their_transactions() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,50
+withdrawal,2,3,10
+deposit,3,4,20
+dispute,3,4,
+chargeback,3,4,
+";
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.process(transactions.as_bytes()).unwrap();
+
+        let mut snapshot = vec![];
+        output::write_csv(
+            ledger.export_accounts(),
+            &mut snapshot,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+
+        let args = Args::parse_from(["toy-ledger", "verify-snapshot", "a.csv", "b.csv"]);
+        assert!(matches!(args.command, Some(Command::VerifySnapshot { .. })));
+
+        assert!(
+            verify_snapshot(&args, snapshot.as_slice(), transactions.as_bytes())
+                .unwrap()
+                .is_empty()
+        );
+
+        // Dropping the withdrawal leaves client 2 diverging.
+        let replayed = transactions.replace("withdrawal,2,3,10\n", "");
+        assert_eq!(
+            verify_snapshot(&args, snapshot.as_slice(), replayed.as_bytes()).unwrap(),
+            vec!["client 2: expected available 40.0, held 0.0, total 40.0, locked false, got available 50.0, held 0.0, total 50.0, locked false"]
+        );
+
+        // A shortfall left by a chargeback is only written in the total.
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,70
+dispute,1,1,
+chargeback,1,1,
+";
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--track-shortfall",
+            "verify-snapshot",
+            "a.csv",
+            "b.csv",
+        ]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.options = args.ledger_options();
+        ledger.process(transactions.as_bytes()).unwrap();
+
+        let mut snapshot = vec![];
+        output::write_csv(
+            ledger.export_accounts(),
+            &mut snapshot,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(snapshot.clone()).unwrap(),
+            "client,available,held,total,locked\n1,0.0,0.0,-70.0,true\n"
+        );
+
+        assert!(
+            verify_snapshot(&args, snapshot.as_slice(), transactions.as_bytes())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn resuming_from_saved_state_should_match_processing_in_one_go() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,10025
+deposit,2,2,5000
+withdrawal,1,3,2050
+deposit,3,4,2000
+dispute,1,1,
+withdrawal,2,5,1000
+deposit,1,3,500
+dispute,2,2,
+chargeback,2,2,
+resolve,1,1,
+";
+        let args = Args::parse_from(["toy-ledger", "--amount-scale", "2", "--track-shortfall"]);
+        let mut whole = Ledger::new(HashMap::new(), BTreeMap::new());
+        whole.options = args.ledger_options();
+        process(&args, &mut whole, transactions.as_bytes(), usize::MAX).unwrap();
+
+        let mut before = Ledger::new(HashMap::new(), BTreeMap::new());
+        before.options = args.ledger_options();
+        process(&args, &mut before, transactions.as_bytes(), 4).unwrap();
+        let mut saved = vec![];
+        state::write_state(&before, &mut saved).unwrap();
+
+        // Resumed after the deposits which are disputed, and the
+        // withdrawal whose ID is reused, later on.
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--amount-scale",
+            "2",
+            "--track-shortfall",
+            "--skip-rows",
+            "4",
+        ]);
+        let mut resumed = resumed_ledger(&args, saved.as_slice()).unwrap();
+        process(&args, &mut resumed, transactions.as_bytes(), usize::MAX).unwrap();
+
+        assert_eq!(resumed.accounts, whole.accounts);
+        assert_eq!(resumed.accounts[&1].available_funds, 79.75);
+        assert_eq!(resumed.accounts[&2].shortfall, 10.0);
+        assert_eq!(resumed.accounts[&2].locked_by_tx, Some(2));
+
+        let (mut resumed_state, mut whole_state) = (vec![], vec![]);
+        state::write_state(&resumed, &mut resumed_state).unwrap();
+        state::write_state(&whole, &mut whole_state).unwrap();
+        assert_eq!(resumed_state, whole_state);
+    }
+
+    #[test]
+    fn drifted_totals_should_be_recomputed_or_rejected() {
+        let accounts = "\
+client,available,held,total,locked
+1,100.0,5.0,105.0,false
+2,40.0,10.0,45.0,false
+";
+
+        let read = read_accounts(accounts.as_bytes(), CheckAction::Warn).unwrap();
+        assert_eq!(read.get(&2).unwrap().total(), 50.0);
+        assert_eq!(read.get(&2).unwrap().shortfall, 0.0);
+
+        let err = read_accounts(accounts.as_bytes(), CheckAction::Error).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "client 2 total 45 isn't available plus held, recomputed as 50"
+        );
+
+        // Only an account with no available funds can have a shortfall.
+        let read = read_accounts(
+            "\
+client,available,held,total,locked
+1,0.0,100.0,0.0,false
+"
+            .as_bytes(),
+            CheckAction::Error,
+        )
+        .unwrap();
+        assert_eq!(read.get(&1).unwrap().shortfall, 100.0);
+        assert_eq!(read.get(&1).unwrap().total(), 0.0);
+    }
+
+    #[test]
+    fn clients_should_be_attributed_to_the_first_file_they_appear_in() {
+        let mut origins = HashMap::new();
+
+        let first = record_client_origins(&mut origins, 0, &HashSet::from([1, 2]));
+        let second = record_client_origins(&mut origins, 1, &HashSet::from([3, 2, 1]));
+        let third = record_client_origins(&mut origins, 2, &HashSet::from([4]));
+
+        assert!(first.is_empty());
+        assert_eq!(second, vec![1, 2]);
+        assert!(third.is_empty());
+        assert_eq!(origins, HashMap::from([(1, 0), (2, 0), (3, 1), (4, 2)]));
+    }
+
+    #[test]
+    fn accounts_should_be_tagged_with_their_first_partner() {
+        let args = Args::parse_from([
+            "toy-ledger",
+            "a.csv",
+            "b.csv",
+            "c.csv",
+            "--partner-id",
+            "acme",
+            "--partner-id",
+            "globex",
+            "--partner-id",
+            "acme",
+        ]);
+        let mut origins = HashMap::new();
+        record_client_origins(&mut origins, 0, &HashSet::from([1, 2, 5]));
+        let second = record_client_origins(&mut origins, 1, &HashSet::from([5, 3, 2]));
+        let third = record_client_origins(&mut origins, 2, &HashSet::from([1, 3]));
+
+        assert_eq!(
+            partner_conflicts(&args, &origins, 1, &second),
+            [
+                "client 2 from partner globex was first seen from partner acme",
+                "client 5 from partner globex was first seen from partner acme",
+            ]
+        );
+        // Client 1 is back with the partner it was first seen from.
+        assert_eq!(
+            partner_conflicts(&args, &origins, 2, &third),
+            ["client 3 from partner acme was first seen from partner globex"]
+        );
+
+        let partners = client_partners(&args, &origins);
+        assert_eq!(
+            partners,
+            HashMap::from([(1, "acme"), (2, "acme"), (3, "globex"), (5, "acme")])
+        );
+
+        let ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let accounts = [Account::new(3), Account::new(4)];
+        let mut written = vec![];
+        write_selected(&args, &ledger, &accounts, &partners, &mut written).unwrap();
+
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "\
+client,available,held,total,locked,partner
+3,0.0,0.0,0.0,false,globex
+4,0.0,0.0,0.0,false,
+"
+        );
+    }
+
+    #[test]
+    fn exact_duplicate_transactions_should_be_dropped() {
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(100.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            tx_id: 2,
+            client_id: 1,
+            amount: Some(40.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        // Same ID as the deposit but a different amount, so not a retry.
+        let conflicting = Transaction {
+            amount: Some(90.0),
+            ..deposit.clone()
+        };
+
+        let deduped = dedup_transactions([
+            deposit.clone(),
+            withdrawal.clone(),
+            deposit.clone(),
+            conflicting.clone(),
+            withdrawal.clone(),
+        ]);
+
+        assert_eq!(deduped, vec![deposit, withdrawal, conflicting]);
+    }
+
+    #[test]
+    fn transactions_with_nan_amounts_should_equal_themselves() {
+        let transaction = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(f64::NAN),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+
+        assert_eq!(transaction, transaction);
+        assert_eq!(
+            dedup_transactions([transaction.clone(), transaction]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn cash_flows_should_sum_settled_deposits_and_withdrawals() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger
+            .process(
+                "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+withdrawal,1,3,30
+withdrawal,1,4,500
+dispute,1,2,
+chargeback,1,2,
+deposit,2,5,10
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        // The rejected withdrawal and charged back deposit don't count.
+        assert_eq!(
+            output::cash_flows(&ledger),
+            vec![
+                CashFlow {
+                    client: 1,
+                    deposited: 100.0,
+                    withdrawn: 30.0,
+                    net: 70.0,
+                    total: 70.0,
+                },
+                CashFlow {
+                    client: 2,
+                    deposited: 10.0,
+                    withdrawn: 0.0,
+                    net: 10.0,
+                    total: 10.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn verbose_accounts_should_count_disputes_and_activity() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,50
+withdrawal,1,3,50
+dispute,1,1,
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            output::verbose_accounts(&ledger),
+            vec![VerboseAccount {
+                client: 1,
+                available: -100.0,
+                held: 100.0,
+                total: 0.0,
+                locked: false,
+                lock_state: "",
+                locked_by_tx: None,
+                disputed_count: 1,
+                activity: 3,
+                note: "",
+            }]
+        );
+    }
+
+    #[test]
+    fn verbose_accounts_should_note_underwater_accounts() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,100
+dispute,1,1,
+chargeback,1,1,
+",
+        )
+        .unwrap();
+
+        let mut output = vec![];
+        output::write_csv(
+            output::verbose_accounts(&ledger),
+            &mut output,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,available,held,total,locked,lock_state,locked_by_tx,disputed_count,activity,note
+1,-100.0,0.0,-100.0,true,frozen,1,0,2,underwater
+"
+        );
+    }
+
+    #[test]
+    fn verbose_accounts_should_tell_closed_from_frozen() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,100
+deposit,2,3,20
+dispute,2,3,
+chargeback,2,3,
+deposit,3,4,0.1
+deposit,3,5,0.2
+withdrawal,3,6,0.1
+dispute,3,5,
+chargeback,3,5,
+",
+        )
+        .unwrap();
+
+        let states: Vec<(u16, &str)> = output::verbose_accounts(&ledger)
+            .iter()
+            .map(|account| (account.client, account.lock_state))
+            .collect();
+
+        // Client 3 is left with float residue, which still counts as closed.
+        assert_ne!(ledger.accounts.get(&3).unwrap().available_funds, 0.0);
+        assert_eq!(states, vec![(1, ""), (2, "frozen"), (3, "closed")]);
+    }
+
+    #[test]
+    fn resolving_a_disputed_deposit_restores_balances() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,1
+resolve,1,1
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 10.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 2,
+            }
+        );
+    }
+
+    /// Activity between the dispute and the resolve of a withdrawal
+    /// shouldn't change what the resolve returns to the client.
+    #[test]
+    fn resolving_a_disputed_withdrawal_after_further_activity_restores_balances() {
+        let ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,90
+dispute,1,2
+deposit,1,3,20
+withdrawal,1,4,25
+resolve,1,2
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 95.0,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 4,
+            }
+        );
     }
 
-    let mut wtr = csv::WriterBuilder::new().from_writer(std::io::stdout());
+    #[test]
+    fn resolving_a_disputed_withdrawal_of_a_locked_account_should_fail() {
+        let mut ledger = create_test_ledger(
+            "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,50
+withdrawal,1,3,90
+dispute,1,3
+dispute,1,2
+chargeback,1,2
+",
+        )
+        .unwrap();
+
+        let err = Transaction {
+            tx_type: TransactionType::Resolve,
+            tx_id: 3,
+            client_id: 1,
+            amount: None,
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        }
+        .append_to(&mut ledger)
+        .unwrap_err();
 
-    for (_, account) in ledger.accounts {
-        wtr.serialize(account)
-            .expect("Failed to serialize account.");
+        assert_eq!(err, TransactionError::AccountLocked);
+        assert_eq!(ledger.is_disputed(3), Some(true));
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                client_id: 1,
+                available_funds: 10.0,
+                held_funds: 90.0,
+                status: AccountStatus::Locked,
+                shortfall: 0.0,
+                locked_by_tx: Some(2),
+                open_disputes: 1,
+                transaction_count: 3,
+            }
+        );
     }
 
-    wtr.flush().expect("Failed to write to stdout.");
-}
+    #[test]
+    fn generated_transactions_should_be_reproducible() {
+        let mut first = vec![];
+        let mut second = vec![];
+        let mut third = vec![];
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+        generate(&mut first, 100, 5, 42).unwrap();
+        generate(&mut second, 100, 5, 42).unwrap();
+        generate(&mut third, 100, 5, 43).unwrap();
 
-    use crate::account::Account;
-    use crate::ledger::Ledger;
-    use crate::transaction::{Transaction, TransactionError, TransactionType};
+        assert_eq!(first, second);
+        assert_ne!(first, third);
+    }
 
-    fn create_test_ledger(contents: &str) -> Result<Ledger, TransactionError> {
-        let mut rdr = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .flexible(true)
-            .from_reader(contents.as_bytes());
+    #[test]
+    fn generated_disputes_should_reference_earlier_transactions_of_the_client() {
+        let mut output = vec![];
+        generate(&mut output, 1000, 10, 7).unwrap();
 
-        let mut ledger = Ledger::new(HashMap::new(), HashMap::new());
+        let mut clients = HashMap::new();
+        let mut disputes = 0;
 
-        for transaction in rdr.deserialize::<crate::transaction::Transaction>() {
-            transaction.unwrap().append_to(&mut ledger)?;
+        for transaction in csv::Reader::from_reader(output.as_slice()).deserialize::<Transaction>()
+        {
+            let transaction = transaction.unwrap();
+
+            assert!((1..=10).contains(&transaction.client_id));
+
+            match transaction.tx_type {
+                TransactionType::Dispute => {
+                    assert_eq!(
+                        clients.get(&transaction.tx_id),
+                        Some(&transaction.client_id)
+                    );
+                    disputes += 1;
+                }
+                _ => {
+                    assert!(transaction.amount.is_some());
+                    assert!(clients
+                        .insert(transaction.tx_id, transaction.client_id)
+                        .is_none());
+                }
+            }
         }
 
-        Ok(ledger)
+        assert!(disputes > 0);
     }
 
     #[test]
-    fn account_balances_should_add_up() {
-        let ledger = create_test_ledger(
+    fn generate_should_parse_as_a_subcommand() {
+        let args = Args::parse_from(["toy-ledger", "generate", "--count", "10", "--seed", "3"]);
+
+        assert!(matches!(
+            args.command,
+            Some(Command::Generate {
+                count: 10,
+                clients: 100,
+                seed: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn clients_should_be_selected_by_repeated_or_comma_separated_flags() {
+        let args = Args::parse_from(["toy-ledger", "--client", "1,7", "--client", "42"]);
+
+        assert_eq!(args.clients, vec![1, 7, 42]);
+        assert!(args.selects(7));
+        assert!(!args.selects(2));
+        assert!(Args::parse_from(["toy-ledger"]).selects(2));
+    }
+
+    #[test]
+    fn completions_should_parse_as_a_subcommand() {
+        let args = Args::parse_from(["toy-ledger", "completions", "zsh"]);
+
+        assert!(matches!(
+            args.command,
+            Some(Command::Completions {
+                shell: clap_complete::Shell::Zsh
+            })
+        ));
+        assert!(Args::try_parse_from(["toy-ledger", "completions", "cmd"]).is_err());
+    }
+
+    #[test]
+    fn counted_types_should_include_unknown_types_only_when_seen() {
+        let args = Args::parse_from(["toy-ledger", "--count-only"]);
+        let mut counts = [0; TransactionType::ALL.len() + 1];
+
+        count_types(
+            &args,
             "\
 type,client,tx,amount
-deposit,1,1,1
-deposit,1,3,2
-deposit,2,5,9
-withdrawal,2,6,5
-",
+deposit,1,1,100
+deposit,1,2,not a number
+withdrawal,1,3,30
+dispute,1,1,
+"
+            .as_bytes(),
+            &mut counts,
+        )
+        .unwrap();
+
+        assert_eq!(counts, [2, 1, 1, 0, 0, 0, 0]);
+        assert_eq!(type_counts(&counts).len(), 6);
+
+        count_types(&args, "type,client\nrefund,1\n".as_bytes(), &mut counts).unwrap();
+
+        let mut output = vec![];
+        output::write_csv(
+            type_counts(&counts),
+            &mut output,
+            csv::QuoteStyle::Necessary,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+type,count
+deposit,2
+withdrawal,1
+dispute,1
+resolve,0
+chargeback,0
+close,0
+unknown,1
+"
+        );
+    }
+
+    #[test]
+    fn movement_only_modes_should_total_gross_flows() {
+        let transactions = "\
+type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,30
+dispute,1,1,
+chargeback,1,1,
+deposit,1,3,5
+withdrawal,2,4,10
+";
+
+        let totals = |flag: &str| {
+            let args = Args::parse_from(["toy-ledger", flag]);
+            let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+            process(&args, &mut ledger, transactions.as_bytes(), usize::MAX).unwrap();
+
+            ledger
+                .accounts
+                .values()
+                .map(|account| (account.client_id, account.total(), account.is_locked()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(totals("--deposit-only"), vec![(1, 105.0, false)]);
+        assert_eq!(
+            totals("--withdrawal-only"),
+            vec![(1, 30.0, false), (2, 10.0, false)]
+        );
+        assert!(
+            Args::try_parse_from(["toy-ledger", "--deposit-only", "--withdrawal-only"]).is_err()
+        );
+    }
+
+    #[test]
+    fn amounts_should_parse_with_thousands_separators() {
+        let args = Args::parse_from(["toy-ledger", "--thousands-separator", ","]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        process(
+            &args,
+            &mut ledger,
+            "\
+type,client,tx,amount
+deposit,1,1,\"1,000.50\"
+withdrawal,1,2,\"1,000\"
+dispute,1,2
+"
+            .as_bytes(),
+            usize::MAX,
         )
         .unwrap();
 
@@ -83,330 +5865,1055 @@ withdrawal,2,6,5
             ledger.accounts.get(&1).unwrap(),
             &Account {
                 client_id: 1,
-                available_funds: 3.0,
-                held_funds: 0.0,
-                total_funds: 3.0,
-                is_locked: false,
+                available_funds: 0.5,
+                held_funds: 1000.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 1,
+                transaction_count: 2,
             }
         );
+    }
 
-        assert_eq!(
-            ledger.accounts.get(&2).unwrap(),
+    #[test]
+    fn amounts_should_be_scaled_on_input_and_output() {
+        let args = Args::parse_from(["toy-ledger", "--amount-scale", "2"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        process(
+            &args,
+            &mut ledger,
+            "\
+type,client,tx,amount
+deposit,1,1,10050
+withdrawal,1,2,29
+"
+            .as_bytes(),
+            usize::MAX,
+        )
+        .unwrap();
+
+        assert_account_approx_eq(
+            ledger.accounts.get(&1).unwrap(),
             &Account {
-                client_id: 2,
-                available_funds: 4.0,
+                client_id: 1,
+                available_funds: 100.21,
+                held_funds: 0.0,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 0,
+            },
+            1e-9,
+        );
+        assert_eq!(
+            scaled_accounts(&args, &ledger),
+            vec![Account {
+                client_id: 1,
+                available_funds: 10021.0,
                 held_funds: 0.0,
-                total_funds: 4.0,
-                is_locked: false,
+                status: AccountStatus::Active,
+                shortfall: 0.0,
+                locked_by_tx: None,
+                open_disputes: 0,
+                transaction_count: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn processing_should_stop_once_past_the_memory_limit() {
+        let deposits = |count: u32| {
+            (1..=count).fold("type,client,tx,amount\n".to_string(), |csv, tx| {
+                csv + &format!("deposit,1,{},1\n", tx)
+            })
+        };
+        let args = Args::parse_from(["toy-ledger", "--max-memory", "0"]);
+
+        // Only checked every 1024 transactions.
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        process(&args, &mut ledger, deposits(1023).as_bytes(), usize::MAX).unwrap();
+        assert!(ledger.estimated_memory() > 0);
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let err = process(&args, &mut ledger, deposits(2000).as_bytes(), usize::MAX).unwrap_err();
+        assert!(err.is::<MemoryLimitExceeded>());
+        assert_eq!(ledger.transactions.len(), 1024);
+    }
+
+    #[test]
+    fn numeric_type_codes_should_parse_when_enabled() {
+        let input = "\
+type,client,tx,amount
+1,1,1,100
+2,1,2,30
+3,1,1,
+";
+
+        let args = Args::parse_from(["toy-ledger", "--numeric-types"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        process(&args, &mut ledger, input.as_bytes(), usize::MAX).unwrap();
+
+        assert_eq!(
+            ledger.accounts.get(&1).unwrap(),
+            &Account {
+                available_funds: -30.0,
+                held_funds: 100.0,
+                open_disputes: 1,
+                transaction_count: 2,
+                ..Account::new(1)
             }
         );
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        assert!(process(
+            &Args::parse_from(["toy-ledger"]),
+            &mut ledger,
+            input.as_bytes(),
+            usize::MAX
+        )
+        .is_err());
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        assert!(process(
+            &args,
+            &mut ledger,
+            "type,client,tx,amount\n7,1,1,100\n".as_bytes(),
+            usize::MAX
+        )
+        .is_err());
     }
 
     #[test]
-    fn disputes_of_unknown_transactions_should_fail() {
-        let err = create_test_ledger(
+    fn amounts_with_thousands_separators_should_fail_by_default() {
+        let args = Args::parse_from(["toy-ledger"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let result = process(
+            &args,
+            &mut ledger,
             "\
 type,client,tx,amount
-deposit,1,1,1
-deposit,2,2,2
-deposit,1,3,2
-dispute,1,5,
-",
-        )
-        .unwrap_err();
+deposit,1,1,\"1,000.50\"
+"
+            .as_bytes(),
+            usize::MAX,
+        );
 
-        assert_eq!(err, TransactionError::TransactionNotFound);
+        assert!(result.is_err());
     }
 
     #[test]
-    fn valid_disputes_should_hold_funds() {
-        let ledger = create_test_ledger(
+    fn scientific_notation_amounts_should_normalize_to_plain_decimals() {
+        let format = AmountFormat::default();
+
+        assert_eq!(format.normalize("1e3"), "1000");
+        assert_eq!(format.normalize("2.5e-1"), "0.25");
+        assert_eq!(format.normalize("-1.5E+2"), "-150");
+        assert_eq!(format.normalize("1.234567e0"), "1.234567");
+        assert_eq!(format.normalize("1.50"), "1.50");
+        assert!(format.is_exact("1.5e2", 150.0));
+
+        let args = Args::parse_from(["toy-ledger", "--detect-precision-loss=error"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        process(
+            &args,
+            &mut ledger,
             "\
 type,client,tx,amount
-deposit,1,1,1
-deposit,1,3,2
-dispute,1,1,
-",
+deposit,1,1,1e3
+withdrawal,1,2,2.5e-1
+"
+            .as_bytes(),
+            usize::MAX,
         )
         .unwrap();
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 999.75);
 
-        assert!(ledger.accounts.values().eq(vec![&Account {
-            client_id: 1,
-            available_funds: 2.0,
-            held_funds: 1.0,
-            total_funds: 3.0,
-            is_locked: false,
-        }]));
+        // Too precise for `f64` to hold exactly.
+        let err = process(
+            &args,
+            &mut ledger,
+            "type,client,tx,amount\ndeposit,1,3,1.234567e0\n".as_bytes(),
+            usize::MAX,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("tx 3 amount 1.234567 is stored as 1.2345"));
+
+        // Nor is any of them read differently once written back out.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,1e3
+deposit,1,2,2.5e-1
+deposit,1,3,1.234567e0
+";
+        let mut reader = TransactionReader::new(input.as_bytes(), AmountFormat::default())
+            .unwrap()
+            .check_round_trip(true);
+        let mut amounts = vec![];
+
+        while let Some(transaction) = reader.next() {
+            amounts.push(transaction.unwrap().amount.unwrap());
+            assert_eq!(reader.round_trip_mismatches(), []);
+        }
+
+        assert_eq!(amounts, [1000.0, 0.25, 1.234567]);
     }
 
     #[test]
-    fn valid_chargeback_should_lock_account() {
-        let ledger = create_test_ledger(
-            "\
+    fn amounts_should_format_as_written() {
+        let format = AmountFormat {
+            thousands_separator: Some(','),
+            ..AmountFormat::default()
+        };
+
+        assert_eq!(format.format(1234567.25), "1,234,567.25");
+        assert_eq!(format.format(-1000.0), "-1,000");
+        assert_eq!(format.format(999.5), "999.5");
+        assert_eq!(AmountFormat::default().format(1000.0), "1000");
+    }
+
+    #[test]
+    fn amounts_should_be_exact_only_when_representable() {
+        let plain = AmountFormat::default();
+        let cents = AmountFormat {
+            scale: 2,
+            ..AmountFormat::default()
+        };
+
+        assert!(plain.is_exact("1.50", 1.5));
+        assert!(plain.is_exact("-0.25", -0.25));
+        assert!(plain.is_exact("000", 0.0));
+        assert!(!plain.is_exact("0.1", 0.1));
+        assert!(cents.is_exact("150", 1.5));
+        assert!(!cents.is_exact("10", 0.1));
+        assert_eq!(
+            exact_decimal(0.1),
+            "0.1000000000000000055511151231257827021181583404541015625"
+        );
+    }
+
+    #[test]
+    fn precision_loss_should_be_reported_per_transaction() {
+        let input = "\
 type,client,tx,amount
-deposit,1,1,1
-deposit,1,3,2
+deposit,1,1,1.5
+deposit,1,2,0.1
 dispute,1,1,
-chargeback,1,1,
-",
+";
+        let mut reader = TransactionReader::new(input.as_bytes(), AmountFormat::default())
+            .unwrap()
+            .detect_precision_loss(true);
+
+        let mut losses = vec![];
+        while let Some(transaction) = reader.next() {
+            transaction.unwrap();
+            losses.push(reader.precision_loss().map(|loss| loss.tx_id));
+        }
+
+        assert_eq!(losses, vec![None, Some(2), None]);
+
+        let args = Args::parse_from(["toy-ledger", "--detect-precision-loss=error"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let err = process(&args, &mut ledger, input.as_bytes(), usize::MAX).unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("tx 2 amount 0.1 is stored as 0.1000"));
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 1.5);
+    }
+
+    #[test]
+    fn recorded_transactions_should_round_trip_unless_reformatted() {
+        let input = "\
+type,client,tx,amount,comment,channel
+1,1,1,\"1,000.5\",refund,web
+1,1,2,\"2,000.50\",,web
+2,1,3,1.5,,
+3,1,1,,,
+";
+        let format = AmountFormat {
+            thousands_separator: Some(','),
+            ..AmountFormat::default()
+        };
+        let mut reader = TransactionReader::new(input.as_bytes(), format)
+            .unwrap()
+            .numeric_types(true)
+            .check_round_trip(true);
+
+        let mut mismatches = vec![];
+        while let Some(transaction) = reader.next() {
+            transaction.unwrap();
+            mismatches.extend(reader.round_trip_mismatches().iter().cloned());
+        }
+
+        // Only the trailing zero of tx 2 is lost. Unknown columns aren't
+        // kept so aren't compared.
+        assert_eq!(
+            mismatches,
+            vec![RoundTripMismatch {
+                tx_id: 2,
+                column: "amount".to_string(),
+                written: "2,000.50".to_string(),
+                rewritten: "2,000.5".to_string(),
+            }]
+        );
+    }
+    #[test]
+    fn thousands_separator_should_not_be_the_decimal_point() {
+        assert!(Args::try_parse_from(["toy-ledger", "--thousands-separator", "."]).is_err());
+        assert_eq!(
+            AmountFormat {
+                thousands_separator: Some('_'),
+                ..AmountFormat::default()
+            }
+            .normalize("1_000_000.25"),
+            "1000000.25"
+        );
+    }
+
+    #[test]
+    fn semicolon_delimited_amounts_should_parse_with_decimal_commas() {
+        let args = Args::parse_from(["toy-ledger", "--delimiter", ";", "--decimal-separator", ","]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        process(
+            &args,
+            &mut ledger,
+            "\
+type;client;tx;amount
+deposit;1;1;100,50
+withdrawal;1;2;0,25
+deposit;1;3;7
+"
+            .as_bytes(),
+            usize::MAX,
+        )
+        .unwrap();
+
+        let account = ledger.accounts.get(&1).unwrap();
+        assert_eq!(account.available_funds, 107.25);
+        assert_eq!(account.transaction_count, 3);
+    }
+
+    #[test]
+    fn decimal_separators_should_not_be_the_delimiter() {
+        let args = Args::parse_from(["toy-ledger", "--decimal-separator", ","]);
+        assert!(args.check_separators().is_err());
+
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--decimal-separator",
+            ",",
+            "--thousands-separator",
+            ",",
+            "--delimiter",
+            ";",
+        ]);
+        assert!(args.check_separators().is_err());
+
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--decimal-separator",
+            ",",
+            "--thousands-separator",
+            " ",
+            "--delimiter",
+            ";",
+        ]);
+        assert!(args.check_separators().is_ok());
+        assert!(Args::try_parse_from(["toy-ledger", "--decimal-separator", "5"]).is_err());
+    }
+
+    #[test]
+    fn duplicate_header_columns_should_be_rejected() {
+        for (header, column) in [
+            ("type,client,tx,tx,amount", "tx"),
+            ("type,client,tx,amount,date,timestamp", "timestamp"),
+        ] {
+            let err = TransactionReader::new(
+                format!("{}\ndeposit,1,1,1,5\n", header).as_bytes(),
+                AmountFormat::default(),
+            )
+            .err()
+            .unwrap();
+
+            let csv::ErrorKind::Io(err) = err.kind() else {
+                panic!("expected an io error, got {:?}", err);
+            };
+            assert_eq!(
+                err.get_ref().unwrap().downcast_ref::<BadHeader>(),
+                Some(&BadHeader {
+                    column: column.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn schema_markers_should_be_skipped() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        ledger
+            .process(
+                "\
+#schema=2
+type,client,tx,amount,timestamp,memo
+deposit,1,1,5,,
+"
+                .as_bytes(),
+            )
+            .unwrap();
+
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 5.0);
+
+        let reader = TransactionReader::new(
+            "#schema=2\ntype,client,tx,amount,timestamp,memo\n".as_bytes(),
+            AmountFormat::default(),
         )
         .unwrap();
 
-        assert!(ledger.accounts.values().eq(vec![&Account {
-            client_id: 1,
-            available_funds: 2.0,
-            held_funds: 0.0,
-            total_funds: 2.0,
-            is_locked: true,
-        }]));
+        assert_eq!(reader.schema_version(), Some(2));
+        assert_eq!(reader.check_schema(2), Ok(()));
+    }
+
+    #[test]
+    fn inputs_should_match_the_expected_schema() {
+        let args = Args::parse_from(["toy-ledger", "--expect-schema", "2"]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let err = process(
+            &args,
+            &mut ledger,
+            "\
+type,client,tx,amount,currency
+deposit,1,1,5,EUR
+"
+            .as_bytes(),
+            usize::MAX,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "expected schema version 2, missing columns date, comment, unexpected columns currency"
+        );
+        assert!(ledger.accounts.is_empty());
+
+        let err = process(
+            &args,
+            &mut ledger,
+            "#schema=1\ntype,client,tx,amount,date,comment\n".as_bytes(),
+            usize::MAX,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "expected schema version 2, the file is marked as version 1"
+        );
+        assert!(Args::try_parse_from(["toy-ledger", "--expect-schema", "3"]).is_err());
+    }
+
+    #[test]
+    fn amounts_should_format_with_decimal_separators() {
+        let format = AmountFormat {
+            thousands_separator: Some(' '),
+            decimal_separator: Some(','),
+            ..AmountFormat::default()
+        };
+
+        assert_eq!(format.normalize("1 000,50"), "1000.50");
+        assert_eq!(format.normalize("1,5e2"), "150");
+        assert_eq!(format.format(1000.5), "1 000,5");
+        assert_eq!(format.format(-0.25), "-0,25");
+    }
+
+    #[test]
+    fn accounts_with_equal_totals_should_sort_by_client() {
+        let mut accounts: Vec<Account> = [(4, 10.0), (2, 50.0), (9, 10.0), (1, 10.0), (3, 70.0)]
+            .into_iter()
+            .map(|(client_id, available_funds)| Account {
+                available_funds,
+                ..Account::new(client_id)
+            })
+            .collect();
+
+        accounts.sort_by(output::compare_by_total);
+
+        assert!(accounts
+            .iter()
+            .map(|account| account.client_id)
+            .eq([3, 2, 1, 4, 9]));
     }
 
     #[test]
-    fn disputes_of_non_matching_client_id_should_fail() {
-        let err = create_test_ledger(
-            "\
-type,client,tx,amount
-deposit,1,1,1
-dispute,2,1,
-",
-        )
-        .unwrap_err();
+    fn funds_should_be_formatted_alike_in_every_output() {
+        let funds = [0.1 + 0.2, 100.0, -0.5, 1e21, 1.5e-7];
+        let accounts: Vec<Account> = funds
+            .iter()
+            .enumerate()
+            .map(|(id, &funds)| Account {
+                available_funds: funds,
+                ..Account::new(id as u16)
+            })
+            .collect();
 
-        assert_eq!(err, TransactionError::Unauthorized);
+        let mut csv = vec![];
+        output::write_csv(&accounts, &mut csv, csv::QuoteStyle::Necessary).unwrap();
+        let mut json = vec![];
+        output::write_json(&accounts, &mut json, false).unwrap();
+        let (csv, json) = (
+            String::from_utf8(csv).unwrap(),
+            String::from_utf8(json).unwrap(),
+        );
+
+        let formatted: Vec<String> = funds
+            .iter()
+            .map(|&funds| format_funds(funds, None))
+            .collect();
+        assert_eq!(
+            formatted,
+            ["0.30000000000000004", "100.0", "-0.5", "1e21", "1.5e-7"]
+        );
+
+        for (account, formatted) in csv.lines().skip(1).zip(&formatted) {
+            assert_eq!(account.split(',').nth(1), Some(formatted.as_str()));
+        }
+        // serde_json writes exponents with a sign, otherwise the same.
+        for formatted in &formatted {
+            let formatted = formatted.replace("e21", "e+21");
+            assert!(json.contains(&format!("\"available\":{},", formatted)));
+        }
+
+        assert_eq!(format_funds(0.1 + 0.2, Some(4)), "0.3000");
+
+        // Traces, comparisons and amounts written back as input too.
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 1,
+            client_id: 3,
+            amount: Some(1e21),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        };
+        let (_, trace) = apply_explained(
+            &deposit,
+            &mut ledger,
+            Some(1),
+            AmountFormat::default(),
+            None,
+        );
+        assert!(trace.unwrap().contains("available: 0.0 -> 1e21\n"));
+
+        let mismatches = compare_accounts(
+            BTreeMap::from([(4, accounts[4].clone())]),
+            vec![Account::new(4)],
+        );
+        assert_eq!(
+            mismatches,
+            ["client 4: expected available 1.5e-7, held 0.0, total 1.5e-7, locked false, got available 0.0, held 0.0, total 0.0, locked false"]
+        );
+
+        let format = AmountFormat {
+            thousands_separator: Some(','),
+            ..AmountFormat::default()
+        };
+        assert_eq!(format.format(1e21), "1e21");
+        assert_eq!(format.format(1.5e-7), "1.5e-7");
+        assert_eq!(format.format(1000.0), "1,000");
     }
 
-    /// This test checks the case where a user spends and then
-    /// attempts to dispute their original deposit. The account
-    /// should be locked and further transactions prevented.
-    ///
-    /// deposits funds (tx#1)
-    /// purchases assets (tx#2)
-    /// withdraws funds (tx#3)
-    /// disputes deposit
-    /// resolve dispute
     #[test]
-    fn prevent_malicious_actor() {
+    fn summary_footer_should_total_accounts() {
         let ledger = create_test_ledger(
             "\
 type,client,tx,amount
 deposit,1,1,100
-withdrawal,1,2,50
-withdrawal,1,3,50
+deposit,2,2,50.5
+deposit,3,3,20
 dispute,1,1,
-chargeback,1,1,
+dispute,3,3,
+chargeback,3,3,
 ",
         )
         .unwrap();
 
+        let mut output = vec![];
+        output::write_summary_footer(ledger.accounts.values(), &mut output).unwrap();
+
         assert_eq!(
-            ledger.accounts.get(&1).unwrap(),
-            &Account {
-                client_id: 1,
-                held_funds: 0.0,
-                available_funds: -100.0,
-                total_funds: -100.0,
-                is_locked: true,
-            }
+            String::from_utf8(output).unwrap(),
+            "\
+# accounts: 3
+# total available: 50.5
+# total held: 100.0
+# locked accounts: 1
+"
         );
     }
 
-    /// If an account is locked and then a dispute is made against a
-    /// transaction it has made the transaction should not be marked
-    /// as disputed.
     #[test]
-    fn disputes_of_locked_accounts_should_fail() {
-        let err = create_test_ledger(
+    fn all_inactive_statuses_should_serialize_as_locked() {
+        let accounts: Vec<Account> = [
+            AccountStatus::Active,
+            AccountStatus::Locked,
+            AccountStatus::Frozen,
+            AccountStatus::Suspended,
+            AccountStatus::Closed,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, status)| Account {
+            status,
+            ..Account::new(i as u16 + 1)
+        })
+        .collect();
+
+        let mut output = vec![];
+        output::write_csv(&accounts, &mut output, csv::QuoteStyle::Necessary).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
             "\
-type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,50
-dispute,1,2,
-chargeback,1,2,
-dispute,1,2,
-",
-        )
+client,available,held,total,locked
+1,0.0,0.0,0.0,false
+2,0.0,0.0,0.0,true
+3,0.0,0.0,0.0,true
+4,0.0,0.0,0.0,true
+5,0.0,0.0,0.0,true
+"
+        );
+    }
+
+    #[test]
+    fn suspended_accounts_should_reject_transactions() {
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        ledger.accounts.insert(
+            1,
+            Account {
+                status: AccountStatus::Suspended,
+                ..Account::new(1)
+            },
+        );
+
+        let err = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 1,
+            client_id: 1,
+            amount: Some(100.0),
+            disputed: false,
+            settled: 0.0,
+            date: None,
+            comment: None,
+            metadata: Default::default(),
+        }
+        .append_to(&mut ledger)
         .unwrap_err();
 
         assert_eq!(err, TransactionError::AccountLocked);
     }
 
     #[test]
-    fn deposits_without_an_amount_should_fail() {
-        let err = create_test_ledger(
+    fn locked_accounts_should_deserialize_as_locked_status() {
+        let accounts: Vec<Account> = csv::Reader::from_reader(
             "\
-type,client,tx,amount
-deposit,1,1,
-",
+client,available,held,total,locked
+1,1.0,0.0,1.0,false
+2,1.0,0.0,1.0,true
+"
+            .as_bytes(),
         )
-        .unwrap_err();
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .unwrap();
 
-        assert_eq!(err, TransactionError::Malformed);
+        assert_eq!(accounts[0].status, AccountStatus::Active);
+        assert_eq!(accounts[1].status, AccountStatus::Locked);
     }
 
     #[test]
-    fn withdrawals_without_an_amount_should_fail() {
-        let err = create_test_ledger(
-            "\
-type,client,tx,amount
-withdrawal,1,1,
-",
-        )
-        .unwrap_err();
+    fn stopped_inputs_should_not_be_read_further() {
+        let stop = AtomicBool::new(false);
+        let mut read = 0;
+        let mut items = until_stopped(
+            std::iter::from_fn(|| {
+                read += 1;
+                Some(read)
+            }),
+            &stop,
+        );
 
-        assert_eq!(err, TransactionError::Malformed);
+        assert_eq!(items.next(), Some(1));
+        assert_eq!(items.next(), Some(2));
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(items.next(), None);
+        drop(items);
+        assert_eq!(read, 2);
     }
 
     #[test]
-    fn process_rows_which_omit_final_comma() {
+    fn exported_accounts_should_be_sorted_by_client() {
         let ledger = create_test_ledger(
             "\
 type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,90
-dispute,1,1
-resolve,1,1
-dispute,1,1
-chargeback,1,1
+deposit,3,1,3.0
+deposit,1,2,1.0
+deposit,2,3,2.0
 ",
         )
         .unwrap();
 
+        let clients: Vec<u16> = ledger
+            .export_accounts()
+            .iter()
+            .map(|account| account.client_id)
+            .collect();
+
+        assert_eq!(clients, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn followed_rows_should_wait_for_complete_lines() {
+        let mut buffer = FollowBuffer::default();
+
+        assert_eq!(buffer.push(b"type,client,"), None);
+        assert_eq!(buffer.push(b"tx,amount\ndeposit,1,1,1"), None);
         assert_eq!(
-            ledger.accounts.get(&1).unwrap(),
-            &Account {
-                held_funds: 0.0,
-                available_funds: -90.0,
-                total_funds: -90.0,
-                is_locked: true,
-                client_id: 1,
-            }
+            buffer.push(b"00\ndeposit,1,2,"),
+            Some(b"type,client,tx,amount\ndeposit,1,1,100\n".to_vec())
+        );
+        assert_eq!(buffer.push(b""), None);
+        assert_eq!(
+            buffer.push(b"50\nwithdrawal,1,3,20\n"),
+            Some(b"type,client,tx,amount\ndeposit,1,2,50\nwithdrawal,1,3,20\n".to_vec())
         );
     }
 
     #[test]
-    fn withdrawing_more_than_available_should_fail() {
-        let err = create_test_ledger(
-            "\
-type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,120
-",
-        )
-        .unwrap_err();
+    fn followed_rows_should_keep_the_schema_marker_with_the_header() {
+        let mut buffer = FollowBuffer::default();
 
-        assert_eq!(err, TransactionError::InsufficientFunds);
+        assert_eq!(buffer.push(b"#schema=1\n"), None);
+        assert_eq!(
+            buffer.push(b"type,client,tx,amount\ndeposit,1,1,100\n"),
+            Some(b"#schema=1\ntype,client,tx,amount\ndeposit,1,1,100\n".to_vec())
+        );
     }
 
-    /// This is counter-intuitive as the client doesn't have
-    /// any available funds to cover their held funds. Total
-    /// funds here does still reflect the true amount though.
     #[test]
-    fn disputes_of_withdrawal_should_increase_held_funds_but_not_available_funds() {
-        let ledger = create_test_ledger(
-            "\
-type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,90
-dispute,1,2
-",
+    fn following_should_stop_once_interrupted() {
+        let path =
+            std::env::temp_dir().join(format!("toy-ledger-follow-{}.csv", std::process::id()));
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,100\n").unwrap();
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let stop = AtomicBool::new(false);
+        let mut emitted = 0;
+
+        crate::follow::follow(
+            &path,
+            &mut ledger,
+            std::time::Duration::from_millis(1),
+            &stop,
+            |ledger, document| {
+                ledger.process(document)?;
+                Ok(())
+            },
+            |_| {
+                emitted += 1;
+                stop.store(true, Ordering::SeqCst);
+            },
         )
         .unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-        assert_eq!(
-            ledger.accounts.get(&1).unwrap(),
-            &Account {
-                client_id: 1,
-                available_funds: 10.0,
-                held_funds: 90.0,
-                total_funds: 100.0,
-                is_locked: false,
-            }
-        );
+        assert_eq!(emitted, 1);
+        assert_eq!(ledger.accounts[&1].available_funds, 100.0);
     }
 
     #[test]
-    fn resolving_a_disputed_withdrawal_restores_balances() {
-        let ledger = create_test_ledger(
-            "\
-type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,90
-dispute,1,2
-resolve,1,2
-",
+    fn followed_rows_should_be_applied_like_processed_ones() {
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--follow",
+            "--deposit-only",
+            "--limit",
+            "4",
+            "input.csv",
+        ]);
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let mut read = 0;
+
+        // The malformed row is skipped rather than ending the follow, and
+        // counts towards the limit like the withdrawal left out.
+        process_followed(
+            &args,
+            &mut ledger,
+            b"type,client,tx,amount\ndeposit,1,1,100\nbogus,1,2,5\n",
+            &mut read,
+        )
+        .unwrap();
+        process_followed(
+            &args,
+            &mut ledger,
+            b"type,client,tx,amount\nwithdrawal,1,3,5\ndeposit,1,4,5\ndeposit,1,5,5\n",
+            &mut read,
         )
         .unwrap();
 
+        assert_eq!(read, 4);
+        assert_eq!(ledger.accounts[&1].available_funds, 105.0);
+    }
+
+    #[cfg(feature = "serve")]
+    #[test]
+    fn served_ledgers_should_keep_state_across_requests() {
+        use tiny_http::Method;
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+        let (status, body) = crate::serve::route(
+            &Method::Post,
+            "/transactions",
+            false,
+            b"type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,50\n",
+            &mut ledger,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body, br#"{"rejected":1,"transactions":2}"#);
+
+        let (status, _) = crate::serve::route(
+            &Method::Post,
+            "/transactions",
+            true,
+            br#"{"type": "withdrawal", "client": 1, "tx": 3, "amount": 4}"#,
+            &mut ledger,
+        );
+        assert_eq!(status, 200);
+
+        let (status, body) =
+            crate::serve::route(&Method::Get, "/accounts", false, b"", &mut ledger);
+        assert_eq!(status, 200);
         assert_eq!(
-            ledger.accounts.get(&1).unwrap(),
-            &Account {
-                client_id: 1,
-                available_funds: 100.0,
-                held_funds: 0.0,
-                total_funds: 100.0,
-                is_locked: false,
-            }
+            String::from_utf8(body).unwrap(),
+            "[{\"client\":1,\"available\":6.0,\"held\":0.0,\"total\":6.0,\"locked\":false}]\n"
         );
+
+        let (status, _) =
+            crate::serve::route(&Method::Post, "/transactions", true, b"{", &mut ledger);
+        assert_eq!(status, 400);
+        let (status, _) =
+            crate::serve::route(&Method::Get, "/transactions", false, b"", &mut ledger);
+        assert_eq!(status, 405);
     }
 
+    #[cfg(feature = "watch")]
     #[test]
-    fn chargeback_on_a_disputed_withdrawal_removes_held_funds() {
-        let ledger = create_test_ledger(
-            "\
-type,client,tx,amount
-deposit,1,1,100
-withdrawal,1,2,90
-dispute,1,2
-chargeback,1,2
-",
+    fn watched_directories_should_skip_files_already_processed() {
+        use crate::watch::{process_new, ProcessedFiles};
+
+        let dir = std::env::temp_dir().join(format!("toy-ledger-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("b.csv"),
+            "type;client;tx;amount\nwithdrawal;1;2;3000\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("a.csv"),
+            "type;client;tx;amount\ndeposit;1;1;10000\n",
         )
         .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not transactions").unwrap();
+
+        // Files are read with the same options as any other input.
+        let args = Args::parse_from([
+            "toy-ledger",
+            "--delimiter",
+            ";",
+            "--amount-scale",
+            "2",
+            "--watch",
+            dir.to_str().unwrap(),
+        ]);
+        let mut apply = |ledger: &mut Ledger, file| {
+            process(&args, ledger, file, usize::MAX)?;
+            Ok(())
+        };
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let mut emitted = vec![];
+        let mut emit = |ledger: &mut Ledger| emitted.push(ledger.accounts[&1].available_funds);
+        let mut processed = ProcessedFiles::open(&dir).unwrap();
 
+        // Files are processed in name order, writing after each.
         assert_eq!(
-            ledger.accounts.get(&1).unwrap(),
-            &Account {
-                client_id: 1,
-                available_funds: 10.0,
-                held_funds: 0.0,
-                total_funds: 10.0,
-                is_locked: true,
-            }
+            process_new(&dir, &mut ledger, &mut processed, &mut apply, &mut emit).unwrap(),
+            2
         );
+        assert_eq!(
+            process_new(&dir, &mut ledger, &mut processed, &mut apply, &mut emit).unwrap(),
+            0
+        );
+
+        // Watching again only picks up the new file.
+        std::fs::write(
+            dir.join("c.csv"),
+            "type;client;tx;amount\ndeposit;1;3;500\n",
+        )
+        .unwrap();
+        let mut processed = ProcessedFiles::open(&dir).unwrap();
+        assert_eq!(
+            process_new(&dir, &mut ledger, &mut processed, &mut apply, &mut emit).unwrap(),
+            1
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(emitted, vec![100.0, 70.0, 75.0]);
     }
 
+    #[cfg(feature = "sqlite")]
     #[test]
-    fn second_transaction_with_duplicate_id_should_fail() {
-        let mut ledger = create_test_ledger(
+    fn ledgers_should_be_written_to_sqlite() {
+        let ledger = create_test_ledger(
             "\
 type,client,tx,amount
 deposit,1,1,100
+deposit,2,2,50
+withdrawal,1,3,30
+dispute,1,1,
 ",
         )
         .unwrap();
 
-        let err = Transaction {
-            tx_type: TransactionType::Withdrawal,
-            tx_id: 1,
-            client_id: 1,
-            amount: Some(90.0),
-            disputed: false,
-        }
-        .append_to(&mut ledger)
-        .unwrap_err();
+        let path = std::env::temp_dir().join(format!("toy-ledger-{}.sqlite", std::process::id()));
+        ledger.write_sqlite(&path).unwrap();
+        // Writing again should replace rather than duplicate the rows.
+        ledger.write_sqlite(&path).unwrap();
 
-        // Rejects adding new transaction.
-        assert_eq!(err, TransactionError::DuplicateTransactionID);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let accounts: Vec<(u16, f64, f64, bool)> = conn
+            .prepare("SELECT client, available, held, locked FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let disputed: Vec<(u32, String)> = conn
+            .prepare("SELECT tx, type FROM transactions WHERE disputed")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
 
-        // Maintains original transaction.
         assert_eq!(
-            ledger.transactions.get(&1).unwrap(),
-            &Transaction {
+            accounts,
+            vec![(1, -30.0, 100.0, false), (2, 50.0, 0.0, false)]
+        );
+        assert_eq!(disputed, vec![(1, "deposit".to_string())]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn streamed_transactions_should_yield_each_result() {
+        use futures::StreamExt;
+
+        let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+        let transactions = futures::stream::iter([
+            Transaction {
                 tx_type: TransactionType::Deposit,
                 tx_id: 1,
                 client_id: 1,
                 amount: Some(100.0),
                 disputed: false,
-            }
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+            Transaction {
+                tx_type: TransactionType::Withdrawal,
+                tx_id: 2,
+                client_id: 1,
+                amount: Some(120.0),
+                disputed: false,
+                settled: 0.0,
+                date: None,
+                comment: None,
+                metadata: Default::default(),
+            },
+        ]);
+
+        let results: Vec<_> =
+            futures::executor::block_on(ledger.process_stream(transactions).collect());
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(AccountSnapshot {
+                    client_id: 1,
+                    available_funds: 100.0,
+                    held_funds: 0.0,
+                    total_funds: 100.0,
+                    status: AccountStatus::Active,
+                }),
+                Err(TransactionError::InsufficientFunds)
+            ]
         );
+        assert_eq!(ledger.accounts.get(&1).unwrap().available_funds, 100.0);
+    }
+
+    fn small_dispute_cycles(count: u32) -> String {
+        let mut contents = String::from("type,client,tx,amount\n");
+
+        for tx_id in 1..=count {
+            contents += &format!("deposit,1,{},0.0{:03}\n", tx_id, tx_id % 1000);
+        }
+
+        for tx_id in 1..=count {
+            contents += &format!("dispute,1,{}\n", tx_id);
+        }
+
+        for tx_id in 1..=count {
+            contents += &format!("resolve,1,{}\n", tx_id);
+        }
+
+        contents
+    }
+
+    #[test]
+    fn unrounded_disputes_of_small_amounts_leave_residual_held_funds() {
+        let ledger = create_test_ledger(&small_dispute_cycles(500)).unwrap();
+
+        // Documents the float drift that rounding fixes.
+        assert_ne!(ledger.accounts.get(&1).unwrap().held_funds, 0.0);
+    }
+
+    #[test]
+    fn rounded_disputes_of_small_amounts_release_all_held_funds() {
+        let options = LedgerOptions {
+            precision: Some(4),
+            ..LedgerOptions::default()
+        };
+
+        let ledger = create_test_ledger_with_options(&small_dispute_cycles(500), options).unwrap();
+        let account = ledger.accounts.get(&1).unwrap();
+
+        assert_eq!(account.held_funds, 0.0);
+        assert!(account.held_funds.is_sign_positive());
+        assert_eq!(account.available_funds, 12.525);
     }
 }
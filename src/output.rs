@@ -0,0 +1,489 @@
+use std::{cmp::Ordering, collections::HashMap, io::Write, str::FromStr};
+
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::{
+    account::{format_funds, Account},
+    ledger::Ledger,
+};
+
+/// Writes rows, such as accounts, as CSV preceded by a header row.
+pub fn write_csv<T: Serialize, W: Write>(
+    rows: impl IntoIterator<Item = T>,
+    writer: W,
+    quote_style: csv::QuoteStyle,
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::WriterBuilder::new()
+        .quote_style(quote_style)
+        .from_writer(writer);
+
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes rows, such as accounts, as a single JSON array. When `pretty`
+/// is set the array is indented for reading and diffing, otherwise it is
+/// written on a single line.
+pub fn write_json<T: Serialize, W: Write>(
+    rows: impl IntoIterator<Item = T>,
+    mut writer: W,
+    pretty: bool,
+) -> Result<(), serde_json::Error> {
+    let rows: Vec<T> = rows.into_iter().collect();
+
+    if pretty {
+        serde_json::to_writer_pretty(&mut writer, &rows)?;
+    } else {
+        serde_json::to_writer(&mut writer, &rows)?;
+    }
+
+    writeln!(writer).map_err(serde_json::Error::io)
+}
+
+/// Writes rows, such as accounts, as a table padded into aligned columns
+/// for reading in a terminal. Columns which hold only numbers are right
+/// aligned, with any fractional numbers written to `precision` places if
+/// given. Not meant to be parsed, use CSV or JSON for that.
+pub fn write_table<T: Serialize, W: Write>(
+    rows: impl IntoIterator<Item = T>,
+    mut writer: W,
+    precision: Option<u32>,
+) -> Result<(), csv::Error> {
+    // Written as CSV first so the header and fields match that output.
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    let csv = wtr.into_inner().map_err(|err| err.into_error())?;
+
+    let mut records: Vec<Vec<String>> = vec![];
+    for record in csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv.as_slice())
+        .records()
+    {
+        records.push(record?.iter().map(str::to_string).collect());
+    }
+
+    let Some((header, rows)) = records.split_first_mut() else {
+        return Ok(());
+    };
+
+    let columns = header.len();
+    let numeric: Vec<bool> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .all(|row| row[column].is_empty() || row[column].parse::<f64>().is_ok())
+        })
+        .collect();
+
+    if let Some(precision) = precision {
+        for row in rows.iter_mut() {
+            for (field, _) in row.iter_mut().zip(&numeric).filter(|(_, &numeric)| numeric) {
+                if let (true, Ok(number)) = (field.contains('.'), field.parse::<f64>()) {
+                    *field = format_funds(number, Some(precision));
+                }
+            }
+        }
+    }
+
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            records
+                .iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    for (index, row) in records.iter().enumerate() {
+        let line: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(column, field)| {
+                if numeric[column] {
+                    format!("{:>width$}", field, width = widths[column])
+                } else {
+                    format!("{:<width$}", field, width = widths[column])
+                }
+            })
+            .collect();
+        writeln!(writer, "{}", line.join("  ").trim_end())?;
+
+        if index == 0 {
+            let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+            writeln!(writer, "{}", rule.join("  "))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A column of the accounts output, named as in its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl AccountColumn {
+    /// Every column, in the order accounts are written by default.
+    pub const ALL: [AccountColumn; 5] = [
+        AccountColumn::Client,
+        AccountColumn::Available,
+        AccountColumn::Held,
+        AccountColumn::Total,
+        AccountColumn::Locked,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountColumn::Client => "client",
+            AccountColumn::Available => "available",
+            AccountColumn::Held => "held",
+            AccountColumn::Total => "total",
+            AccountColumn::Locked => "locked",
+        }
+    }
+}
+
+impl FromStr for AccountColumn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AccountColumn::ALL
+            .into_iter()
+            .find(|column| column.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = AccountColumn::ALL
+                    .iter()
+                    .map(|column| column.name())
+                    .collect();
+                format!(
+                    "unknown column \"{}\", expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// An account written with only the given columns, in their order.
+#[derive(Debug)]
+pub struct AccountColumns<'a> {
+    pub account: &'a Account,
+    pub columns: &'a [AccountColumn],
+}
+
+impl Serialize for AccountColumns<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let account = self.account;
+        let mut state = serializer.serialize_struct("Account", self.columns.len())?;
+
+        for &column in self.columns {
+            let name = column.name();
+
+            match column {
+                AccountColumn::Client => state.serialize_field(name, &account.client_id)?,
+                AccountColumn::Available => {
+                    state.serialize_field(name, &account.available_funds)?
+                }
+                AccountColumn::Held => state.serialize_field(name, &account.held_funds)?,
+                AccountColumn::Total => state.serialize_field(name, &account.total())?,
+                AccountColumn::Locked => state.serialize_field(name, &account.is_locked())?,
+            }
+        }
+
+        state.end()
+    }
+}
+
+/// An account with the partner whose input first introduced the client,
+/// written as an extra `partner` column, empty if there isn't one.
+#[derive(Debug)]
+pub struct PartnerAccount<'a> {
+    pub account: &'a Account,
+    pub partner: Option<&'a str>,
+}
+
+impl Serialize for PartnerAccount<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let account = self.account;
+        let mut state = serializer.serialize_struct("Account", 6)?;
+        state.serialize_field("client", &account.client_id)?;
+        state.serialize_field("available", &account.available_funds)?;
+        state.serialize_field("held", &account.held_funds)?;
+        state.serialize_field("total", &account.total())?;
+        state.serialize_field("locked", &account.is_locked())?;
+        state.serialize_field("partner", &self.partner)?;
+        state.end()
+    }
+}
+
+/// Orders accounts by total funds, highest first. Accounts with equal
+/// totals are ordered by ascending client ID, so the order never depends
+/// on the order the accounts were found in.
+pub fn compare_by_total(left: &Account, right: &Account) -> Ordering {
+    right
+        .total()
+        .total_cmp(&left.total())
+        .then_with(|| left.client_id.cmp(&right.client_id))
+}
+
+/// Writes totals across `accounts` as `#` comment lines, for appending
+/// after CSV output. Strict CSV readers will reject the comments.
+pub fn write_summary_footer<'a, W: Write>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    mut writer: W,
+) -> std::io::Result<()> {
+    let (mut count, mut available, mut held, mut locked) = (0, 0.0, 0.0, 0);
+
+    for account in accounts {
+        count += 1;
+        available += account.available_funds;
+        held += account.held_funds;
+        locked += account.is_locked() as usize;
+    }
+
+    writeln!(writer, "# accounts: {}", count)?;
+    writeln!(
+        writer,
+        "# total available: {}",
+        format_funds(available, None)
+    )?;
+    writeln!(writer, "# total held: {}", format_funds(held, None))?;
+    writeln!(writer, "# locked accounts: {}", locked)
+}
+
+/// An account annotated with details derived from the ledger's
+/// transactions, for human review.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct VerboseAccount {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+
+    /// `closed` for a locked account left with no funds, eg. after a
+    /// chargeback took everything, or `frozen` for a locked account still
+    /// holding or owing funds. Empty for an active account.
+    pub lock_state: &'static str,
+
+    /// ID of the chargeback which locked the account, if any.
+    pub locked_by_tx: Option<u32>,
+
+    /// Number of the client's transactions currently disputed.
+    pub disputed_count: usize,
+
+    /// Number of the client's recorded transactions. Only deposits and
+    /// withdrawals are recorded so disputes and their outcomes aren't counted.
+    pub activity: usize,
+
+    /// Human readable note, eg. when the client owes more than they hold.
+    pub note: &'static str,
+}
+
+/// Funds within this of zero are taken as zero when telling closed
+/// accounts from frozen ones, since chargebacks leave float residue.
+const ZERO_EPSILON: f64 = 1e-9;
+
+fn lock_state(account: &Account) -> &'static str {
+    let is_zero = |funds: f64| libm::fabs(funds) <= ZERO_EPSILON;
+
+    if !account.is_locked() {
+        ""
+    } else if is_zero(account.available_funds)
+        && is_zero(account.held_funds)
+        && is_zero(account.shortfall)
+    {
+        "closed"
+    } else {
+        "frozen"
+    }
+}
+
+/// Annotates each of the ledger's accounts, in the same order as
+/// `Ledger::export_accounts`.
+pub fn verbose_accounts(ledger: &Ledger) -> Vec<VerboseAccount> {
+    let mut counts: HashMap<u16, (usize, usize)> = HashMap::new();
+
+    for tx in ledger.transactions.values() {
+        let (disputed_count, activity) = counts.entry(tx.client_id).or_default();
+        *disputed_count += tx.disputed as usize;
+        *activity += 1;
+    }
+
+    ledger
+        .accounts
+        .values()
+        .map(|account| {
+            let (disputed_count, activity) =
+                counts.get(&account.client_id).copied().unwrap_or_default();
+
+            VerboseAccount {
+                client: account.client_id,
+                available: account.available_funds,
+                held: account.held_funds,
+                total: account.total(),
+                locked: account.is_locked(),
+                lock_state: lock_state(account),
+                locked_by_tx: account.locked_by_tx,
+                disputed_count,
+                activity,
+                note: if account.total() < 0.0 {
+                    "underwater"
+                } else {
+                    ""
+                },
+            }
+        })
+        .collect()
+}
+
+/// A client's funds in and out over the recorded transactions, as
+/// written by `--cashflow`, next to their account's total.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CashFlow {
+    pub client: u16,
+    pub deposited: f64,
+    pub withdrawn: f64,
+    pub net: f64,
+    pub total: f64,
+}
+
+/// Sums the funds each client's recorded deposits and withdrawals
+/// settled, in the same order as `Ledger::export_accounts`. Rejected and
+/// charged back transactions settled nothing so don't count, and nor do
+/// transactions which have been pruned.
+pub fn cash_flows(ledger: &Ledger) -> Vec<CashFlow> {
+    let mut flows: HashMap<u16, (f64, f64)> = HashMap::new();
+
+    for tx in ledger.transactions.values() {
+        let (deposited, withdrawn) = flows.entry(tx.client_id).or_default();
+
+        if tx.settled > 0.0 {
+            *deposited += tx.settled;
+        } else {
+            *withdrawn -= tx.settled;
+        }
+    }
+
+    ledger
+        .accounts
+        .values()
+        .map(|account| {
+            let (deposited, withdrawn) = flows.get(&account.client_id).copied().unwrap_or_default();
+
+            CashFlow {
+                client: account.client_id,
+                deposited,
+                withdrawn,
+                net: deposited - withdrawn,
+                total: account.total(),
+            }
+        })
+        .collect()
+}
+
+/// A recorded transaction as written to the JSON audit export, with any
+/// metadata from JSON input alongside the known fields.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AuditRecord<'a> {
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Option<f64>,
+    pub disputed: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<&'a str>,
+
+    #[serde(flatten)]
+    pub metadata: &'a serde_json::Map<String, serde_json::Value>,
+}
+
+/// Lists the ledger's recorded transactions in ascending ID order for
+/// the audit export. Only deposits and withdrawals are recorded.
+pub fn audit_records(ledger: &Ledger) -> Vec<AuditRecord<'_>> {
+    let mut records: Vec<AuditRecord> = ledger
+        .transactions
+        .values()
+        .map(|tx| AuditRecord {
+            tx_type: tx.tx_type.name(),
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.amount,
+            disputed: tx.disputed,
+            date: tx.date.map(|date| date.to_string()),
+            comment: tx.comment.as_deref(),
+            metadata: &tx.metadata,
+        })
+        .collect();
+
+    records.sort_unstable_by_key(|record| record.tx);
+    records
+}
+
+/// Number of transactions of a type, as written by `--count-only`. Types
+/// which aren't known are counted as `unknown`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TypeCount {
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub count: usize,
+}
+
+/// A transaction which failed to apply, as logged by `--error-format
+/// json`. `row` is the transaction's row in the input, counting a CSV
+/// header as row 1, and `byte` its offset in CSV input.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RejectedTransaction {
+    pub tx: u32,
+    pub client: u16,
+    #[serde(rename = "type")]
+    pub tx_type: &'static str,
+    pub error: &'static str,
+    pub row: usize,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub byte: Option<u64>,
+}
+
+/// An account as written to the change feed, stamped with the Unix time
+/// in milliseconds the change was written at.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AccountChange {
+    pub timestamp_ms: u64,
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl AccountChange {
+    pub fn new(timestamp_ms: u64, account: &Account) -> Self {
+        AccountChange {
+            timestamp_ms,
+            client: account.client_id,
+            available: account.available_funds,
+            held: account.held_funds,
+            total: account.total(),
+            locked: account.is_locked(),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::error::Error;
+
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use toy_ledger::{
+    ledger::Ledger,
+    output,
+    transaction::{json_transactions, AmountFormat, Transaction, TransactionReader},
+};
+
+/// Serves the ledger over HTTP on `addr`, eg. `127.0.0.1:8080`, handling
+/// one request at a time. The ledger lives as long as the process, so
+/// transactions from every request accumulate. Never returns unless the
+/// server can't be started.
+///
+/// - `POST /transactions` applies a CSV body, or a JSON one when sent as
+///   `application/json`, and reports how many transactions were rejected.
+/// - `GET /accounts` returns the accounts as a JSON array.
+pub fn serve(addr: &str, ledger: &mut Ledger) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = Server::http(addr)?;
+
+    for request in server.incoming_requests() {
+        if let Err(err) = handle(request, ledger) {
+            eprintln!("Failed to respond: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(mut request: Request, ledger: &mut Ledger) -> std::io::Result<()> {
+    let is_json = request.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str().starts_with("application/json")
+    });
+
+    let mut body = vec![];
+    request.as_reader().read_to_end(&mut body)?;
+
+    let (status, body) = route(request.method(), request.url(), is_json, &body, ledger);
+    let content_type = Header::from_bytes("Content-Type", "application/json")
+        .expect("Content-Type is a valid header.");
+
+    request.respond(
+        Response::from_data(body)
+            .with_status_code(status)
+            .with_header(content_type),
+    )
+}
+
+/// Handles a request, returning the status code and JSON body to respond
+/// with. Query strings are ignored.
+pub fn route(
+    method: &Method,
+    url: &str,
+    is_json: bool,
+    body: &[u8],
+    ledger: &mut Ledger,
+) -> (u16, Vec<u8>) {
+    let path = url.split('?').next().unwrap_or(url);
+
+    let (status, body) = match (method, path) {
+        (Method::Post, "/transactions") => match read_transactions(body, is_json) {
+            Ok(transactions) => {
+                let count = transactions.len();
+                let rejected = ledger.apply_all(transactions).len();
+
+                (200, json!({ "transactions": count, "rejected": rejected }))
+            }
+            Err(err) => (400, json!({ "error": err.to_string() })),
+        },
+        (Method::Get, "/accounts") => {
+            let mut accounts = vec![];
+            output::write_json(ledger.export_accounts(), &mut accounts, false)
+                .expect("Writing to a Vec can't fail.");
+
+            return (200, accounts);
+        }
+        (_, "/transactions" | "/accounts") => (405, json!({ "error": "method not allowed" })),
+        _ => (404, json!({ "error": "not found" })),
+    };
+
+    (status, body.to_string().into_bytes())
+}
+
+/// Reads every transaction in a request body before any are applied, so
+/// a malformed body changes nothing.
+fn read_transactions(body: &[u8], is_json: bool) -> Result<Vec<Transaction>, Box<dyn Error>> {
+    if is_json {
+        Ok(json_transactions(body, AmountFormat::default()).collect::<Result<_, _>>()?)
+    } else {
+        Ok(TransactionReader::new(body, AmountFormat::default())?.collect::<Result<_, _>>()?)
+    }
+}
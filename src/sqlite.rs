@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::ledger::Ledger;
+
+const SCHEMA: &str = "
+DROP TABLE IF EXISTS accounts;
+DROP TABLE IF EXISTS transactions;
+
+CREATE TABLE accounts (
+    client INTEGER PRIMARY KEY,
+    available REAL NOT NULL,
+    held REAL NOT NULL,
+    total REAL NOT NULL,
+    locked INTEGER NOT NULL,
+    shortfall REAL NOT NULL,
+    locked_by_tx INTEGER
+);
+
+CREATE TABLE transactions (
+    tx INTEGER PRIMARY KEY,
+    type TEXT NOT NULL,
+    client INTEGER NOT NULL,
+    amount REAL,
+    disputed INTEGER NOT NULL,
+    date TEXT,
+    comment TEXT
+);
+";
+
+impl Ledger {
+    /// Writes the accounts and recorded transactions to `accounts` and
+    /// `transactions` tables in the SQLite database at `path`, so the
+    /// processed state can be queried with SQL. Any existing tables of
+    /// those names are replaced.
+    pub fn write_sqlite<P: AsRef<Path>>(&self, path: P) -> Result<(), rusqlite::Error> {
+        let mut conn = Connection::open(path)?;
+        let tx = conn.transaction()?;
+
+        tx.execute_batch(SCHEMA)?;
+
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO accounts (client, available, held, total, locked, shortfall, locked_by_tx)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+
+            for account in self.export_accounts() {
+                insert.execute(params![
+                    account.client_id,
+                    account.available_funds,
+                    account.held_funds,
+                    account.total(),
+                    account.is_locked(),
+                    account.shortfall,
+                    account.locked_by_tx,
+                ])?;
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT INTO transactions (tx, type, client, amount, disputed, date, comment)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+
+            for transaction in self.transactions.values() {
+                insert.execute(params![
+                    transaction.tx_id,
+                    transaction.tx_type.name(),
+                    transaction.client_id,
+                    transaction.amount,
+                    transaction.disputed,
+                    transaction.date.map(|date| date.to_string()),
+                    transaction.comment,
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use toy_ledger::{
+    account::{Account, AccountStatus},
+    ledger::Ledger,
+    transaction::{Date, Transaction, TransactionType},
+};
+
+/// Everything about an account, unlike the output which leaves out what
+/// it derives from the transactions.
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountState {
+    client: u16,
+    available: f64,
+    held: f64,
+    shortfall: f64,
+    status: AccountStatus,
+    locked_by_tx: Option<u32>,
+    open_disputes: u32,
+    transaction_count: u32,
+}
+
+/// Everything about a recorded transaction, including whether it's
+/// disputed and what it has settled.
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionState {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    disputed: bool,
+    settled: f64,
+    date: Option<Date>,
+    comment: Option<String>,
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A ledger's accounts and recorded transactions, as written by
+/// `--save-state` and read by `--resume-from`. Funds are kept as held by
+/// the ledger, not in the input's amount scale.
+#[derive(Debug, Serialize, Deserialize)]
+struct LedgerState {
+    accounts: Vec<AccountState>,
+    transactions: Vec<TransactionState>,
+}
+
+/// Writes the ledger's accounts and recorded transactions as JSON, so
+/// that `read_state` can carry on from them as though processing never
+/// stopped. The ledger's options aren't written. Neither is the order
+/// transactions were recorded in, so with `--prune-after` the
+/// transactions read back are never pruned.
+pub fn write_state<W: Write>(ledger: &Ledger, writer: W) -> serde_json::Result<()> {
+    let accounts = ledger
+        .accounts
+        .values()
+        .map(|account| AccountState {
+            client: account.client_id,
+            available: account.available_funds,
+            held: account.held_funds,
+            shortfall: account.shortfall,
+            status: account.status,
+            locked_by_tx: account.locked_by_tx,
+            open_disputes: account.open_disputes,
+            transaction_count: account.transaction_count,
+        })
+        .collect();
+
+    let mut transactions: Vec<TransactionState> = ledger
+        .transactions
+        .values()
+        .map(|transaction| TransactionState {
+            tx_type: transaction.tx_type,
+            client: transaction.client_id,
+            tx: transaction.tx_id,
+            amount: transaction.amount,
+            disputed: transaction.disputed,
+            settled: transaction.settled,
+            date: transaction.date,
+            comment: transaction.comment.clone(),
+            metadata: transaction.metadata.clone(),
+        })
+        .collect();
+    // Sorted so the same ledger is always written the same way.
+    transactions.sort_by_key(|transaction| transaction.tx);
+
+    serde_json::to_writer(
+        writer,
+        &LedgerState {
+            accounts,
+            transactions,
+        },
+    )
+}
+
+/// Reads a ledger written by `write_state`, with default options.
+pub fn read_state<R: Read>(reader: R) -> serde_json::Result<Ledger> {
+    let state: LedgerState = serde_json::from_reader(reader)?;
+
+    let accounts: BTreeMap<u16, Account> = state
+        .accounts
+        .into_iter()
+        .map(|account| {
+            (
+                account.client,
+                Account {
+                    client_id: account.client,
+                    available_funds: account.available,
+                    held_funds: account.held,
+                    shortfall: account.shortfall,
+                    status: account.status,
+                    locked_by_tx: account.locked_by_tx,
+                    open_disputes: account.open_disputes,
+                    transaction_count: account.transaction_count,
+                },
+            )
+        })
+        .collect();
+
+    let transactions: HashMap<u32, Transaction> = state
+        .transactions
+        .into_iter()
+        .map(|transaction| {
+            (
+                transaction.tx,
+                Transaction {
+                    tx_type: transaction.tx_type,
+                    client_id: transaction.client,
+                    tx_id: transaction.tx,
+                    amount: transaction.amount,
+                    disputed: transaction.disputed,
+                    settled: transaction.settled,
+                    date: transaction.date,
+                    comment: transaction.comment,
+                    metadata: transaction.metadata,
+                },
+            )
+        })
+        .collect();
+
+    Ok(Ledger::new(transactions, accounts))
+}
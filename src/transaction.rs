@@ -1,10 +1,29 @@
-use std::{collections::HashMap, error::Error, fmt::Display};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+};
+use core::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+#[cfg(feature = "std")]
+use std::{
+    collections::HashSet,
+    error::Error,
+    io::{Chain, Cursor, Read},
+};
 
-use serde::Deserialize;
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{account::Account, ledger::Ledger};
+use crate::{
+    account::{format_funds, Account, AccountSnapshot, AccountStatus},
+    ledger::{Ledger, LedgerOptions},
+    Entry, Map,
+};
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum TransactionType {
@@ -90,6 +109,106 @@ pub enum TransactionType {
     /// tx specified doesn't exist, or the tx isn't under dispute, you can
     /// ignore chargeback and assume this is an error on our partner's side.
     Chargeback,
+
+    /// A close looks like
+    ///
+    /// |type  |client |id     |amount |
+    /// |------|-------|-------|-------|
+    /// |close |1      |9      |       |
+    ///
+    /// A close pays out the client's available funds, recorded as a
+    /// withdrawal under the close's tx ID, and permanently locks the
+    /// account. It's refused while the client has open disputes, and only
+    /// accepted when the ledger allows closes.
+    Close,
+}
+
+impl TransactionType {
+    /// Every type, in the order of their numeric codes.
+    pub const ALL: [TransactionType; 6] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Close,
+    ];
+
+    /// Maps a legacy numeric type code to its type, eg. `1` for a deposit.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(TransactionType::Deposit),
+            2 => Some(TransactionType::Withdrawal),
+            3 => Some(TransactionType::Dispute),
+            4 => Some(TransactionType::Resolve),
+            5 => Some(TransactionType::Chargeback),
+            6 => Some(TransactionType::Close),
+            _ => None,
+        }
+    }
+
+    /// Legacy numeric code of the type, the inverse of `from_code`.
+    pub fn code(self) -> u8 {
+        match self {
+            TransactionType::Deposit => 1,
+            TransactionType::Withdrawal => 2,
+            TransactionType::Dispute => 3,
+            TransactionType::Resolve => 4,
+            TransactionType::Chargeback => 5,
+            TransactionType::Close => 6,
+        }
+    }
+
+    /// Name of the type as written in the `type` column.
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Close => "close",
+        }
+    }
+}
+
+/// Tolerance when verifying the funds released by a chargeback.
+const VERIFY_EPSILON: f64 = 1e-9;
+
+/// Tolerance when checking a withdrawal against the available funds, so
+/// that withdrawing what's left isn't refused over float residue, eg.
+/// `0.3 - 0.1` being a hair under `0.2`. Relative to the balance, as the
+/// residue grows with it, see `withdrawal_tolerance`.
+const WITHDRAWAL_EPSILON: f64 = 1e-9;
+
+/// Float residue allowed when withdrawing from a balance of `available`.
+fn withdrawal_tolerance(available: f64) -> f64 {
+    WITHDRAWAL_EPSILON * libm::fabs(available).max(1.0)
+}
+
+/// Fails unless `account` can transact. When `allow_locked` is set an
+/// account locked by a chargeback passes.
+fn ensure_unlocked(account: &Account, allow_locked: bool) -> Result<(), TransactionError> {
+    let usable = match account.status {
+        AccountStatus::Active => true,
+        AccountStatus::Locked => allow_locked,
+        _ => false,
+    };
+
+    if usable {
+        Ok(())
+    } else {
+        Err(TransactionError::AccountLocked)
+    }
+}
+
+/// Fails unless the disputes open on `account` can be resolved or charged
+/// back, which an account frozen for going negative allows.
+fn ensure_settleable(account: &Account) -> Result<(), TransactionError> {
+    match account.status {
+        AccountStatus::Frozen => Ok(()),
+        _ => ensure_unlocked(account, false),
+    }
 }
 
 #[non_exhaustive]
@@ -124,17 +243,891 @@ pub enum TransactionError {
     /// Transaction attempts to reference a transaction created by
     /// a different client.
     Unauthorized,
+
+    /// Transaction attempts to chargeback a deposit whose funds are
+    /// no longer available to the client.
+    UncoveredChargeback,
+
+    /// Transaction attempts to dispute while the client already has the
+    /// maximum number of open disputes.
+    TooManyOpenDisputes,
+
+    /// Transaction is from a client who isn't registered with the ledger,
+    /// or closes the account of a client who doesn't have one.
+    UnknownClient,
+
+    /// Transaction is a deposit or withdrawal from a client who already
+    /// has the maximum number applied.
+    RateLimited,
+
+    /// Transaction was refused by the ledger's `TransactionHook`.
+    Rejected { reason: String },
+
+    /// Transaction attempts to withdraw funds which would leave the
+    /// account with less than the minimum balance available.
+    BelowMinimumBalance,
+
+    /// Transaction is a deposit or withdrawal of more than the maximum
+    /// amount.
+    AmountTooLarge,
+
+    /// Transaction attempts to close an account which still has funds
+    /// held by disputes.
+    HasOpenDisputes,
+
+    /// Transaction attempts to reverse something other than a deposit or
+    /// withdrawal, or one which is under dispute.
+    Irreversible,
+
+    /// A chargeback left held funds negative, or released a different
+    /// amount than was disputed, so the account's held funds had drifted
+    /// from its disputes. The chargeback is still applied. Only checked
+    /// when chargebacks are verified.
+    UnbalancedChargeback {
+        held: f64,
+        released: f64,
+        disputed: f64,
+    },
+
+    /// The ledger has handed out every internal transaction ID, so can't
+    /// record a transaction it generates itself, eg. a sweep or fee.
+    OutOfInternalIds,
 }
 
+impl TransactionError {
+    /// Name of the error as a stable snake case string, eg.
+    /// `insufficient_funds`, for logs which are parsed rather than read.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionError::Malformed => "malformed",
+            TransactionError::DuplicateTransactionID => "duplicate_transaction_id",
+            TransactionError::InsufficientFunds => "insufficient_funds",
+            TransactionError::TransactionNotFound => "transaction_not_found",
+            TransactionError::NotDisputed => "not_disputed",
+            TransactionError::AlreadyDisputed => "already_disputed",
+            TransactionError::Indisputable => "indisputable",
+            TransactionError::AccountLocked => "account_locked",
+            TransactionError::Unauthorized => "unauthorized",
+            TransactionError::UncoveredChargeback => "uncovered_chargeback",
+            TransactionError::TooManyOpenDisputes => "too_many_open_disputes",
+            TransactionError::UnknownClient => "unknown_client",
+            TransactionError::RateLimited => "rate_limited",
+            TransactionError::Rejected { .. } => "rejected",
+            TransactionError::BelowMinimumBalance => "below_minimum_balance",
+            TransactionError::AmountTooLarge => "amount_too_large",
+            TransactionError::HasOpenDisputes => "has_open_disputes",
+            TransactionError::Irreversible => "irreversible",
+            TransactionError::UnbalancedChargeback { .. } => "unbalanced_chargeback",
+            TransactionError::OutOfInternalIds => "out_of_internal_ids",
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl Error for TransactionError {}
 impl Display for TransactionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-// 16 bytes
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+/// Builds a CSV reader for a partner's transaction file.
+#[cfg(feature = "std")]
+pub fn csv_reader<R: Read>(reader: R) -> csv::Reader<R> {
+    delimited_csv_reader(reader, b',')
+}
+
+/// Builds a CSV reader for a partner's transaction file whose fields are
+/// separated by `delimiter`, eg. `;`.
+#[cfg(feature = "std")]
+pub fn delimited_csv_reader<R: Read>(reader: R, delimiter: u8) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(csv::Trim::All) // example file contains space padding
+        .flexible(true)
+        .from_reader(reader)
+}
+
+/// Describes how a partner writes amounts, so they can be rewritten
+/// into the plain form `f64` parses and scaled into whole units.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AmountFormat {
+    /// Character grouping thousands, eg. `,` in `1,000.50`.
+    /// Must be quoted in comma delimited files.
+    pub thousands_separator: Option<char>,
+
+    /// Character separating the fraction, eg. `,` in `100,50`, when it
+    /// isn't `.`. Must be quoted in comma delimited files.
+    pub decimal_separator: Option<char>,
+
+    /// Number of decimal places amounts are shifted by, eg. `2` for
+    /// amounts written as integer cents. `0` takes amounts as-is.
+    pub scale: u32,
+}
+
+impl AmountFormat {
+    /// Whether amounts in this format need rewriting at all.
+    #[cfg(feature = "std")]
+    fn is_plain(&self) -> bool {
+        self.thousands_separator.is_none() && self.decimal_separator.is_none()
+    }
+
+    /// Rewrites an amount into a plain decimal, without thousands
+    /// separators or scientific notation, eg. `1.5e2` as `150`, and with
+    /// `.` separating the fraction.
+    pub fn normalize(&self, amount: &str) -> String {
+        let amount = match self.thousands_separator {
+            Some(separator) => amount.replace(separator, ""),
+            None => amount.to_string(),
+        };
+        let amount = match self.decimal_separator {
+            Some(separator) => amount.replace(separator, "."),
+            None => amount,
+        };
+
+        expand_exponent(&amount).unwrap_or(amount)
+    }
+
+    fn scale_factor(&self) -> f64 {
+        libm::pow(10.0, self.scale as f64)
+    }
+
+    /// Converts a parsed amount into whole units, eg. cents into dollars.
+    pub fn unscale(&self, amount: f64) -> f64 {
+        if self.scale == 0 {
+            return amount;
+        }
+
+        amount / self.scale_factor()
+    }
+
+    /// Converts funds in whole units back into this format's scale for
+    /// output. Scaled amounts are integers, so the result is rounded to
+    /// drop the residue of dividing on the way in.
+    pub fn rescale(&self, funds: f64) -> f64 {
+        if self.scale == 0 {
+            return funds;
+        }
+
+        libm::round(funds * self.scale_factor()) + 0.0
+    }
+
+    /// Writes an amount as it would be written in this format, the inverse
+    /// of normalizing and unscaling it. Amounts are written as by
+    /// `format_funds`, in the shortest form which parses back the same,
+    /// except that whole amounts drop the `.0`, eg. `1000`.
+    pub fn format(&self, amount: f64) -> String {
+        let funds = format_funds(self.rescale(amount), None);
+        let plain = funds.strip_suffix(".0").unwrap_or(&funds).to_string();
+        let point = self.decimal_separator.unwrap_or('.');
+
+        // Exponents, eg. `1e21`, aren't grouped.
+        let Some(separator) = self.thousands_separator.filter(|_| !plain.contains('e')) else {
+            return plain.replace('.', &point.to_string());
+        };
+
+        let (sign, unsigned) = match plain.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", plain.as_str()),
+        };
+        let (whole, fraction) = unsigned.split_at(unsigned.find('.').unwrap_or(unsigned.len()));
+
+        let mut grouped = String::from(sign);
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                grouped.push(separator);
+            }
+            grouped.push(digit);
+        }
+        grouped.push_str(&fraction.replace('.', &point.to_string()));
+
+        grouped
+    }
+
+    /// Whether `amount`, parsed from `text` written in this format, holds
+    /// exactly the value written. Most decimals, eg. `0.1`, have no exact
+    /// `f64` representation. Text which isn't a plain decimal once
+    /// normalized is taken to be exact.
+    pub fn is_exact(&self, text: &str, amount: f64) -> bool {
+        let Some((negative, digits, places)) = split_decimal(&self.normalize(text)) else {
+            return true;
+        };
+
+        split_decimal(&exact_decimal(amount))
+            == Some((negative, digits, places + self.scale as i64))
+    }
+}
+
+/// Rewrites scientific notation, eg. `2.5e-1`, as a plain decimal, eg.
+/// `0.25`, by moving the decimal point rather than going through `f64`,
+/// so nothing is lost. `None` if `text` has no exponent, or isn't a number
+/// with an exponent `f64` could hold.
+fn expand_exponent(text: &str) -> Option<String> {
+    let (mantissa, exponent) = text.split_once(['e', 'E'])?;
+    let exponent: i64 = exponent.parse().ok()?;
+
+    // No `f64` has more digits than the smallest subnormal.
+    if exponent.unsigned_abs() > 1100 {
+        return None;
+    }
+
+    let (sign, unsigned) = match mantissa.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", mantissa.strip_prefix('+').unwrap_or(mantissa)),
+    };
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let digits = [whole, fraction].concat();
+    let point = whole.len() as i64 + exponent;
+
+    let plain = if point <= 0 {
+        format!("0.{}{}", "0".repeat(point.unsigned_abs() as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}", digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        let (whole, fraction) = digits.split_at(point as usize);
+        format!("{}.{}", whole, fraction)
+    };
+
+    Some(format!("{}{}", sign, plain))
+}
+
+/// Writes out every decimal place of `amount`, eg. `0.1` as
+/// `0.1000000000000000055511151231257827021181583404541015625`.
+pub fn exact_decimal(amount: f64) -> String {
+    // No `f64` has more decimal places than the smallest subnormal.
+    let exact = format!("{:.1074}", amount);
+
+    exact
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Splits a plain decimal, eg. `-12.50`, into its sign, significant
+/// digits and number of decimal places, with leading and trailing zeros
+/// dropped so that equal values split equally.
+fn split_decimal(text: &str) -> Option<(bool, String, i64)> {
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let digits = [whole, fraction].concat();
+    let significant = digits.trim_end_matches('0');
+    let places = fraction.len() as i64 - (digits.len() - significant.len()) as i64;
+    let significant = significant.trim_start_matches('0');
+
+    if significant.is_empty() {
+        return Some((false, String::new(), 0));
+    }
+
+    Some((negative, significant.to_string(), places))
+}
+
+/// An amount whose parsed `f64` differs from the decimal written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecisionLoss {
+    pub tx_id: u32,
+
+    /// Amount as written in the input.
+    pub written: String,
+
+    /// Exact value of the amount as stored, see `exact_decimal`.
+    pub stored: String,
+}
+
+impl Display for PrecisionLoss {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "tx {} amount {} is stored as {}",
+            self.tx_id, self.written, self.stored
+        )
+    }
+}
+
+/// A column which reads differently once the transaction parsed from its
+/// row is written back out, eg. an amount of `1.50` written as `1.5`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundTripMismatch {
+    pub tx_id: u32,
+    pub column: String,
+
+    /// Field as written in the input.
+    pub written: String,
+
+    /// Field as written back out from the parsed transaction.
+    pub rewritten: String,
+}
+
+impl Display for RoundTripMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "tx {} {} {:?} is written back as {:?}",
+            self.tx_id, self.column, self.written, self.rewritten
+        )
+    }
+}
+
+/// Columns of each version of the transaction CSV schema, starting at
+/// version 1. `date` and `comment` may also be written as `timestamp` and
+/// `memo`.
+#[cfg(feature = "std")]
+pub const SCHEMA_COLUMNS: [&[&str]; 2] = [
+    &["type", "client", "tx", "amount"],
+    &["type", "client", "tx", "amount", "date", "comment"],
+];
+
+/// Name of the column `header` is read as, eg. `date` for `timestamp`.
+#[cfg(feature = "std")]
+fn canonical_column(header: &str) -> &str {
+    match header {
+        "timestamp" => "date",
+        "memo" => "comment",
+        header => header,
+    }
+}
+
+/// A header naming a column more than once, either exactly or through an
+/// alias such as `timestamp` for `date`. Reported by `TransactionReader`
+/// as a `csv::Error` wrapping an `io::Error` of kind `InvalidData`, whose
+/// inner error is this.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadHeader {
+    /// The column named twice, as written the second time.
+    pub column: String,
+}
+
+#[cfg(feature = "std")]
+impl Error for BadHeader {}
+
+#[cfg(feature = "std")]
+impl Display for BadHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "column {:?} appears more than once in the header",
+            self.column
+        )
+    }
+}
+
+/// Checks no column is named twice in `headers`.
+#[cfg(feature = "std")]
+fn check_header(headers: &csv::StringRecord) -> Result<(), BadHeader> {
+    let mut seen = HashSet::new();
+
+    match headers
+        .iter()
+        .find(|header| !seen.insert(canonical_column(header)))
+    {
+        Some(column) => Err(BadHeader {
+            column: column.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A file which wasn't written for the expected version of the schema.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    pub expected: u32,
+
+    /// Version named by the file's `#schema=` line, if it has one.
+    pub marked: Option<u32>,
+
+    /// Columns of the expected version missing from the header.
+    pub missing: Vec<String>,
+
+    /// Columns in the header which aren't in the expected version.
+    pub unexpected: Vec<String>,
+}
+
+#[cfg(feature = "std")]
+impl Error for SchemaMismatch {}
+
+#[cfg(feature = "std")]
+impl Display for SchemaMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "expected schema version {}", self.expected)?;
+
+        if let Some(marked) = self.marked.filter(|&marked| marked != self.expected) {
+            write!(f, ", the file is marked as version {}", marked)?;
+        }
+        if !self.missing.is_empty() {
+            write!(f, ", missing columns {}", self.missing.join(", "))?;
+        }
+        if !self.unexpected.is_empty() {
+            write!(f, ", unexpected columns {}", self.unexpected.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A reader with any schema marker read off it, see `read_schema_marker`.
+#[cfg(feature = "std")]
+type Unmarked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Reads a leading `#schema=<version>` line off `reader`, returning the
+/// version it names and the reader with any other first line put back.
+/// A marker is left in the cursor as already read, so the cursor's
+/// position is the number of bytes before the header.
+#[cfg(feature = "std")]
+fn read_schema_marker<R: Read>(mut reader: R) -> std::io::Result<(Option<u32>, Unmarked<R>)> {
+    let mut line = vec![];
+    reader.by_ref().take(1).read_to_end(&mut line)?;
+
+    if line != b"#" {
+        return Ok((None, Cursor::new(line).chain(reader)));
+    }
+
+    // Read a byte at a time so nothing past the marker is consumed.
+    while !line.ends_with(b"\n") {
+        if reader.by_ref().take(1).read_to_end(&mut line)? == 0 {
+            break;
+        }
+    }
+
+    let version = core::str::from_utf8(&line)
+        .ok()
+        .and_then(|line| line.trim().strip_prefix("#schema="))
+        .and_then(|version| version.trim().parse().ok());
+
+    match version {
+        Some(version) => {
+            let mut marker = Cursor::new(line);
+            marker.set_position(marker.get_ref().len() as u64);
+
+            Ok((Some(version), marker.chain(reader)))
+        }
+        None => Ok((None, Cursor::new(line).chain(reader))),
+    }
+}
+
+/// Reads transactions from a partner's CSV file, rewriting amounts
+/// written in a non-plain `AmountFormat` before they're parsed.
+#[cfg(feature = "std")]
+pub struct TransactionReader<R> {
+    rdr: csv::Reader<Unmarked<R>>,
+    headers: csv::StringRecord,
+    schema_version: Option<u32>,
+    amount_index: Option<usize>,
+    type_index: Option<usize>,
+    format: AmountFormat,
+    numeric_types: bool,
+    detect_precision_loss: bool,
+    precision_loss: Option<PrecisionLoss>,
+    check_round_trip: bool,
+    round_trip_mismatches: Vec<RoundTripMismatch>,
+    record: csv::StringRecord,
+    marker_len: u64,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> TransactionReader<R> {
+    pub fn new(reader: R, format: AmountFormat) -> Result<Self, csv::Error> {
+        Self::with_delimiter(reader, format, b',')
+    }
+
+    /// Reads a file whose fields are separated by `delimiter` rather than `,`.
+    pub fn with_delimiter(
+        reader: R,
+        format: AmountFormat,
+        delimiter: u8,
+    ) -> Result<Self, csv::Error> {
+        let (schema_version, reader) = read_schema_marker(reader)?;
+        let marker_len = reader.get_ref().0.position();
+        let mut rdr = delimited_csv_reader(reader, delimiter);
+        let headers = rdr.headers()?.clone();
+        check_header(&headers)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        let amount_index = headers.iter().position(|header| header == "amount");
+        let type_index = headers.iter().position(|header| header == "type");
+
+        Ok(TransactionReader {
+            rdr,
+            headers,
+            schema_version,
+            amount_index,
+            type_index,
+            format,
+            numeric_types: false,
+            detect_precision_loss: false,
+            precision_loss: None,
+            check_round_trip: false,
+            round_trip_mismatches: vec![],
+            record: csv::StringRecord::new(),
+            marker_len,
+        })
+    }
+
+    /// The schema version named by a `#schema=<version>` line before the
+    /// header, if the file has one. Any other first line starting with `#`
+    /// is taken as the header.
+    pub fn schema_version(&self) -> Option<u32> {
+        self.schema_version
+    }
+
+    /// Checks the header has exactly the columns of `version` of the
+    /// schema, see `SCHEMA_COLUMNS`, and that the file isn't marked as
+    /// another version.
+    pub fn check_schema(&self, version: u32) -> Result<(), SchemaMismatch> {
+        let expected: &[&str] = version
+            .checked_sub(1)
+            .and_then(|index| SCHEMA_COLUMNS.get(index as usize))
+            .copied()
+            .unwrap_or_default();
+
+        let columns: Vec<&str> = self.headers.iter().map(canonical_column).collect();
+
+        let mismatch = SchemaMismatch {
+            expected: version,
+            marked: self.schema_version,
+            missing: expected
+                .iter()
+                .filter(|column| !columns.contains(column))
+                .map(|column| column.to_string())
+                .collect(),
+            unexpected: columns
+                .iter()
+                .filter(|column| !expected.contains(column))
+                .map(|column| column.to_string())
+                .collect(),
+        };
+
+        if mismatch.marked.is_some_and(|marked| marked != version)
+            || !mismatch.missing.is_empty()
+            || !mismatch.unexpected.is_empty()
+        {
+            return Err(mismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Byte offset in the input of the row last read, eg. to seek straight
+    /// to a row which failed in a large file. `None` before the first row.
+    pub fn byte_offset(&self) -> Option<u64> {
+        self.record
+            .position()
+            .map(|position| self.marker_len + position.byte())
+    }
+
+    /// Reads only the `type` of the next row, for tallying a file without
+    /// parsing every field. `Ok(None)` for a type which isn't known, and
+    /// `None` once the file ends.
+    pub fn next_type(&mut self) -> Option<Result<Option<TransactionType>, csv::Error>> {
+        match self.rdr.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err)),
+        }
+
+        let field = self
+            .type_index
+            .and_then(|index| self.record.get(index))
+            .unwrap_or_default();
+
+        Some(Ok(if self.numeric_types {
+            field.parse().ok().and_then(TransactionType::from_code)
+        } else {
+            TransactionType::ALL
+                .into_iter()
+                .find(|tx_type| tx_type.name() == field)
+        }))
+    }
+
+    /// Reads the `type` column as the numeric codes of `TransactionType::from_code`
+    /// rather than by name. Unknown codes fail to parse like unknown names.
+    pub fn numeric_types(mut self, numeric_types: bool) -> Self {
+        self.numeric_types = numeric_types;
+        self
+    }
+
+    /// Checks each amount parses to exactly the decimal written, see
+    /// `precision_loss`.
+    pub fn detect_precision_loss(mut self, detect_precision_loss: bool) -> Self {
+        self.detect_precision_loss = detect_precision_loss;
+        self
+    }
+
+    /// The precision lost parsing the amount of the transaction last read,
+    /// if any. Only detected when enabled with `detect_precision_loss`.
+    pub fn precision_loss(&self) -> Option<&PrecisionLoss> {
+        self.precision_loss.as_ref()
+    }
+
+    /// Checks that each deposit and withdrawal reads the same once written
+    /// back out in the file's columns, see `round_trip_mismatches`.
+    pub fn check_round_trip(mut self, check_round_trip: bool) -> Self {
+        self.check_round_trip = check_round_trip;
+        self
+    }
+
+    /// The columns of the row last read which didn't survive being parsed
+    /// and written back out. Only checked when enabled with
+    /// `check_round_trip`, and only for deposits and withdrawals since
+    /// they're the transactions recorded.
+    pub fn round_trip_mismatches(&self) -> &[RoundTripMismatch] {
+        &self.round_trip_mismatches
+    }
+
+    /// Writes a transaction back out as a row in the file's columns, with
+    /// amounts in the file's `AmountFormat`. Columns a transaction doesn't keep, such
+    /// as unknown ones, are `None`.
+    fn rewrite(&self, transaction: &Transaction) -> Vec<Option<String>> {
+        self.headers
+            .iter()
+            .map(|header| match header {
+                "type" if self.numeric_types => Some(transaction.tx_type.code().to_string()),
+                "type" => Some(transaction.tx_type.name().to_string()),
+                "client" => Some(transaction.client_id.to_string()),
+                "tx" => Some(transaction.tx_id.to_string()),
+                "amount" => Some(
+                    transaction
+                        .amount
+                        .map(|amount| self.format.format(amount))
+                        .unwrap_or_default(),
+                ),
+                "date" | "timestamp" => Some(
+                    transaction
+                        .date
+                        .map(|date| date.to_string())
+                        .unwrap_or_default(),
+                ),
+                "comment" | "memo" => Some(transaction.comment.clone().unwrap_or_default()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn replace_field(&mut self, index: usize, value: &str) {
+        self.record = self
+            .record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| if i == index { value } else { field })
+            .collect();
+    }
+
+    fn read_transaction(&mut self) -> Result<Option<Transaction>, csv::Error> {
+        self.precision_loss = None;
+        self.round_trip_mismatches.clear();
+
+        if !self.rdr.read_record(&mut self.record)? {
+            return Ok(None);
+        }
+
+        // Scientific notation is expanded whatever the separators, so the
+        // checks below see the plain decimal it stands for.
+        if let Some(index) = self.amount_index {
+            if let Some(plain) = self.record.get(index).and_then(expand_exponent) {
+                self.replace_field(index, &plain);
+            }
+        }
+
+        let row = self.check_round_trip.then(|| self.record.clone());
+
+        let written = self
+            .amount_index
+            .filter(|_| self.detect_precision_loss)
+            .and_then(|index| self.record.get(index))
+            .map(str::to_string);
+
+        if let Some(index) = self.amount_index.filter(|_| !self.format.is_plain()) {
+            if let Some(amount) = self.record.get(index) {
+                let amount = self.format.normalize(amount);
+                self.replace_field(index, &amount);
+            }
+        }
+
+        if let Some(index) = self.type_index.filter(|_| self.numeric_types) {
+            let name = self
+                .record
+                .get(index)
+                .and_then(|code| code.parse().ok())
+                .and_then(TransactionType::from_code)
+                .map(TransactionType::name);
+
+            if let Some(name) = name {
+                self.replace_field(index, name);
+            }
+        }
+
+        let mut transaction: Transaction = self.record.deserialize(Some(&self.headers))?;
+        transaction.amount = transaction.amount.map(|amount| self.format.unscale(amount));
+
+        if let (Some(written), Some(amount)) = (written, transaction.amount) {
+            if !self.format.is_exact(&written, amount) {
+                self.precision_loss = Some(PrecisionLoss {
+                    tx_id: transaction.tx_id,
+                    written,
+                    stored: exact_decimal(amount),
+                });
+            }
+        }
+
+        let recorded = matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+
+        if let Some(row) = row.filter(|_| recorded) {
+            let rewritten = self.rewrite(&transaction);
+
+            for ((column, written), rewritten) in self.headers.iter().zip(&row).zip(rewritten) {
+                let Some(rewritten) = rewritten else {
+                    continue;
+                };
+
+                if written != rewritten {
+                    self.round_trip_mismatches.push(RoundTripMismatch {
+                        tx_id: transaction.tx_id,
+                        column: column.to_string(),
+                        written: written.to_string(),
+                        rewritten,
+                    });
+                }
+            }
+        }
+
+        Ok(Some(transaction))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Iterator for TransactionReader<R> {
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_transaction().transpose()
+    }
+}
+
+/// A transaction as read from JSON input. Fields besides those of
+/// `Transaction` are collected rather than ignored.
+#[cfg(feature = "std")]
+#[derive(Deserialize)]
+struct JsonTransaction {
+    #[serde(flatten)]
+    transaction: Transaction,
+
+    #[serde(flatten)]
+    metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Reads transactions from a stream of JSON objects, eg. one per line,
+/// with the same keys as a CSV file's columns. Any other keys are kept in
+/// `Transaction::metadata`. Amounts are numbers, so only the format's
+/// scale applies.
+#[cfg(feature = "std")]
+pub fn json_transactions<R: Read>(
+    reader: R,
+    format: AmountFormat,
+) -> impl Iterator<Item = Result<Transaction, serde_json::Error>> {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<JsonTransaction>()
+        .map(move |record| {
+            let JsonTransaction {
+                mut transaction,
+                metadata,
+            } = record?;
+
+            transaction.amount = transaction.amount.map(|amount| format.unscale(amount));
+            transaction.metadata = metadata;
+
+            Ok(transaction)
+        })
+}
+
+/// A calendar date, eg. `2022-09-01`. Dates carry no timezone, a
+/// transaction belongs to whichever date the partner recorded for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl FromStr for Date {
+    type Err = TransactionError;
+
+    /// Parses a `YYYY-MM-DD` date. Anything following the date, such as
+    /// the time of an ISO 8601 timestamp, is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let date = s.get(..10).ok_or(TransactionError::Malformed)?;
+        let mut parts = date.split('-');
+
+        let mut next_part = || {
+            parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(TransactionError::Malformed)
+        };
+
+        let year = next_part()?;
+        let month = next_part()?;
+        let day = next_part()?;
+
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(TransactionError::Malformed);
+        }
+
+        Ok(Date {
+            year,
+            month: month as u8,
+            day: day as u8,
+        })
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// 77 bytes
+#[derive(Debug, Deserialize, Clone)]
 pub struct Transaction {
     /// Type of transaction. See `TransactionType` for more information.
     #[serde(rename = "type")]
@@ -151,13 +1144,149 @@ pub struct Transaction {
     /// Using an `f64` here is not advised but done for simplicity.
     /// Transaction amounts should be stored with fixed precision to
     /// ensure correct and precise arithmetic operations.
+    #[serde(default, deserialize_with = "amount_from_field")]
     pub amount: Option<f64>, // 8 bytes
 
     #[serde(skip)]
     pub disputed: bool, // 1 byte
+
+    /// Net amount this transaction has added to the client's available
+    /// funds, including any reimbursement or chargeback of a dispute but
+    /// not funds held by an open one. Stays zero if it was rejected.
+    #[serde(skip)]
+    pub settled: f64, // 8 bytes
+
+    /// Date the transaction was made, if the file has a `date` or
+    /// `timestamp` column.
+    #[serde(default, alias = "timestamp")]
+    pub date: Option<Date>, // 5 bytes
+
+    /// Free text note from the partner, if the file has a `comment` or
+    /// `memo` column. Plays no part in the arithmetic.
+    #[serde(default, alias = "memo")]
+    pub comment: Option<String>, // 24 bytes
+
+    /// Extra fields of a JSON input row, kept as given for the audit
+    /// export. Always empty for CSV input.
+    #[cfg(feature = "std")]
+    #[serde(skip)]
+    pub metadata: serde_json::Map<String, serde_json::Value>, // 24 bytes
+}
+
+/// Reads an amount given as a number or as text, eg. a CSV field. An
+/// empty or blank field is no amount, so `dispute,1,1,` reads the same as
+/// `dispute,1,1`.
+fn amount_from_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Option<f64>;
+
+        fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.write_str("an amount or an empty field")
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_f64<E: serde::de::Error>(self, amount: f64) -> Result<Self::Value, E> {
+            Ok(Some(amount))
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, amount: i64) -> Result<Self::Value, E> {
+            Ok(Some(amount as f64))
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, amount: u64) -> Result<Self::Value, E> {
+            Ok(Some(amount as f64))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, amount: &str) -> Result<Self::Value, E> {
+            match amount.trim() {
+                "" => Ok(None),
+                amount => amount.parse().map(Some).map_err(E::custom),
+            }
+        }
+    }
+
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+/// Transactions are compared on the bit pattern of their amount so that
+/// equality agrees with `Hash`. This means `NaN` amounts equal each other
+/// while `0.0` and `-0.0` don't. Metadata is compared but not hashed.
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        #[cfg(feature = "std")]
+        if self.metadata != other.metadata {
+            return false;
+        }
+
+        self.tx_type == other.tx_type
+            && self.client_id == other.client_id
+            && self.tx_id == other.tx_id
+            && self.amount.map(f64::to_bits) == other.amount.map(f64::to_bits)
+            && self.disputed == other.disputed
+            && self.settled.to_bits() == other.settled.to_bits()
+            && self.date == other.date
+            && self.comment == other.comment
+    }
+}
+
+impl Eq for Transaction {}
+
+impl Hash for Transaction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tx_type.hash(state);
+        self.client_id.hash(state);
+        self.tx_id.hash(state);
+        self.amount.map(f64::to_bits).hash(state);
+        self.disputed.hash(state);
+        self.settled.to_bits().hash(state);
+        self.date.hash(state);
+        self.comment.hash(state);
+    }
+}
+
+/// Drops exact duplicates from a stream of transactions, keeping the first
+/// occurrence of each in order. This makes reprocessing a partner's retried
+/// rows idempotent.
+#[cfg(feature = "std")]
+pub fn dedup_transactions<I: IntoIterator<Item = Transaction>>(
+    transactions: I,
+) -> Vec<Transaction> {
+    let mut seen = HashSet::new();
+
+    transactions
+        .into_iter()
+        .filter(|transaction| seen.insert(transaction.clone()))
+        .collect()
 }
 
 impl Transaction {
+    /// The client whose account the transaction changes, eg. to route it
+    /// to the shard holding that account.
+    pub fn affected_client(&self) -> u16 {
+        self.client_id
+    }
+
+    /// Whether the transaction changes the account of `client_id`.
+    pub fn affects_account(&self, client_id: u16) -> bool {
+        self.affected_client() == client_id
+    }
+
     fn is_disputed(&mut self) -> Result<(), TransactionError> {
         if !self.disputed {
             Err(TransactionError::NotDisputed)
@@ -174,28 +1303,28 @@ impl Transaction {
         }
     }
 
+    /// Amounts must be present and finite, `NaN` or infinite
+    /// amounts would otherwise silently corrupt the account.
     fn get_amount(&self) -> Result<f64, TransactionError> {
-        self.amount.ok_or(TransactionError::Malformed)
+        self.amount
+            .filter(|amount| amount.is_finite())
+            .ok_or(TransactionError::Malformed)
     }
 
-    fn get_account<'a>(
+    /// Returns the client's account, opening one if the client is new.
+    /// Whether the account can transact is left to `ensure_unlocked`.
+    fn get_or_create_account<'a>(
         &self,
-        accounts: &'a mut HashMap<u16, Account>,
-    ) -> Result<&'a mut Account, TransactionError> {
-        let account = accounts
+        accounts: &'a mut BTreeMap<u16, Account>,
+    ) -> &'a mut Account {
+        accounts
             .entry(self.client_id)
-            .or_insert_with(|| Account::new(self.client_id));
-
-        if account.is_locked {
-            return Err(TransactionError::AccountLocked);
-        }
-
-        Ok(account)
+            .or_insert_with(|| Account::new(self.client_id))
     }
 
     fn get_referenced_tx<'a>(
         &self,
-        transactions: &'a mut HashMap<u32, Transaction>,
+        transactions: &'a mut Map<u32, Transaction>,
     ) -> Result<&'a mut Transaction, TransactionError> {
         let referenced_tx = transactions
             .get_mut(&self.tx_id)
@@ -219,88 +1348,392 @@ impl Transaction {
         Ok(referenced_tx)
     }
 
-    /// Appends a transaction to the ledger.
-    /// Applies balance mutations to the accounts.
-    /// Creates accounts where necessary.
-    pub fn append_to(&self, ledger: &mut Ledger) -> Result<(), TransactionError> {
-        match self.tx_type {
-            TransactionType::Deposit | TransactionType::Withdrawal => {
-                // Keep track of this transaction in case there are disputes.
-                // Since we only track deposits and withdrawals we don't need
-                // to check the type of transaction that is disputed since
-                // we will only find those transaction types from a lookup.
-                if let Some(old) = ledger.transactions.insert(self.tx_id, *self) {
-                    // `try_insert` could be used here but
-                    // isn't available in stable Rust.
-                    // Put back the old record.
-                    ledger.transactions.insert(self.tx_id, old);
-
-                    // Don't process the duplicate transaction,
-                    // instead bail with an error.
-                    return Err(TransactionError::DuplicateTransactionID);
+    /// Records a deposit or withdrawal and moves its funds. A rejected
+    /// transaction stays recorded, with nothing settled.
+    fn record(&self, ledger: &mut Ledger) -> Result<AccountSnapshot, TransactionError> {
+        // Keep track of this transaction in case there are disputes.
+        // Since we only track deposits and withdrawals we don't need
+        // to check the type of transaction that is disputed since
+        // we will only find those transaction types from a lookup.
+        // A single lookup both detects a duplicate and records the
+        // transaction, and the record is settled through the same entry.
+        let recorded = match ledger.transactions.entry(self.tx_id) {
+            // Don't process the duplicate transaction,
+            // instead bail with an error.
+            Entry::Occupied(_) => return Err(TransactionError::DuplicateTransactionID),
+            Entry::Vacant(entry) => {
+                // Catches IDs which have since been pruned, but may also
+                // reject IDs which were never seen.
+                if let Some(seen) = &mut ledger.seen_tx_ids {
+                    if seen.insert(self.tx_id) {
+                        return Err(TransactionError::DuplicateTransactionID);
+                    }
                 }
+
+                entry.insert(self.clone())
             }
-            _ => {}
+        };
+
+        let result = self.move_funds(&mut ledger.accounts, ledger.options);
+        if let Ok((_, settled)) = result {
+            recorded.settled = settled;
         }
 
-        match self.tx_type {
-            TransactionType::Deposit => {
-                let amount = self.get_amount()?;
-                let account = self.get_account(&mut ledger.accounts)?;
+        ledger.note_recorded(self.tx_id);
 
-                account.available_funds += amount;
-                account.total_funds = account.available_funds + account.held_funds;
+        result.map(|(snapshot, _)| snapshot)
+    }
+
+    /// Closes the client's account, recording the payout of its available
+    /// funds as a withdrawal so the account's history still adds up.
+    fn close(&self, ledger: &mut Ledger) -> Result<AccountSnapshot, TransactionError> {
+        if !ledger.options.allow_close {
+            return Err(TransactionError::Unauthorized);
+        }
+
+        // Closing creates no account, which would be closed for good.
+        let account = ledger
+            .accounts
+            .get_mut(&self.client_id)
+            .ok_or(TransactionError::UnknownClient)?;
+        ensure_unlocked(account, false)?;
+
+        if account.open_disputes > 0 || account.held_funds > 0.0 {
+            return Err(TransactionError::HasOpenDisputes);
+        }
+
+        // Closing an overdrawn account would write off what's owed.
+        let payout = account.available_funds - account.shortfall;
+        if payout < 0.0 {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        if payout > 0.0 {
+            match ledger.transactions.entry(self.tx_id) {
+                Entry::Occupied(_) => return Err(TransactionError::DuplicateTransactionID),
+                Entry::Vacant(entry) => {
+                    entry.insert(Transaction {
+                        tx_type: TransactionType::Withdrawal,
+                        amount: Some(payout),
+                        disputed: false,
+                        settled: -payout,
+                        ..self.clone()
+                    });
+                }
             }
+        }
+
+        account.available_funds = 0.0;
+        account.held_funds = 0.0;
+        account.shortfall = 0.0;
+        account.status = AccountStatus::Closed;
+        account.locked_by_tx = Some(self.tx_id);
+        let snapshot = account.snapshot();
+
+        if payout > 0.0 {
+            ledger.note_recorded(self.tx_id);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Moves the funds of a deposit or withdrawal, returning the client's
+    /// balances after it and the amount settled.
+    fn move_funds(
+        &self,
+        accounts: &mut BTreeMap<u16, Account>,
+        options: LedgerOptions,
+    ) -> Result<(AccountSnapshot, f64), TransactionError> {
+        let amount = self.get_amount()?;
+
+        if options.max_amount.is_some_and(|max| amount > max) {
+            return Err(TransactionError::AmountTooLarge);
+        }
+
+        let (account, settled) = match self.tx_type {
             TransactionType::Withdrawal => {
-                let amount = self.get_amount()?;
-                let account = self.get_account(&mut ledger.accounts)?;
+                let account = self.get_or_create_account(accounts);
+                ensure_unlocked(account, false)?;
+                let tolerance = withdrawal_tolerance(account.available_funds);
 
-                if amount > account.available_funds {
+                if amount > account.available_funds + tolerance {
                     return Err(TransactionError::InsufficientFunds);
                 }
 
-                account.available_funds -= amount;
-                account.total_funds = account.available_funds + account.held_funds;
+                if options
+                    .min_balance
+                    .is_some_and(|floor| account.available_funds - amount < floor - tolerance)
+                {
+                    return Err(TransactionError::BelowMinimumBalance);
+                }
+
+                (account, -amount)
+            }
+            _ => {
+                let account = self.get_or_create_account(accounts);
+                ensure_unlocked(account, options.allow_deposit_when_locked)?;
+
+                (account, amount)
+            }
+        };
+
+        if options
+            .max_tx_per_client
+            .is_some_and(|max| account.transaction_count >= max)
+        {
+            return Err(TransactionError::RateLimited);
+        }
+
+        let tolerance = withdrawal_tolerance(account.available_funds);
+        account.available_funds += settled;
+        // A withdrawal of what's left drains the account to exactly zero
+        // rather than leaving the residue, which may be negative.
+        if self.tx_type == TransactionType::Withdrawal
+            && libm::fabs(account.available_funds) <= tolerance
+        {
+            account.available_funds = 0.0;
+        }
+        account.transaction_count += 1;
+        account.rebalance(options.track_shortfall);
+        self.freeze_if_negative(account, options);
+
+        Ok((account.snapshot(), settled))
+    }
+
+    /// Locks an active account which has gone negative when the ledger
+    /// freezes such accounts.
+    fn freeze_if_negative(&self, account: &mut Account, options: LedgerOptions) {
+        if options.freeze_on_negative
+            && account.status == AccountStatus::Active
+            && (account.available_funds < 0.0 || account.shortfall > 0.0)
+        {
+            account.status = AccountStatus::Frozen;
+            account.locked_by_tx = Some(self.tx_id);
+        }
+    }
+
+    /// Appends a transaction to the ledger.
+    /// Applies balance mutations to the accounts.
+    /// Creates accounts where necessary.
+    /// Returns the client's balances after the transaction.
+    #[must_use = "a rejected transaction is only reported by the returned error"]
+    pub fn append_to(&self, ledger: &mut Ledger) -> Result<AccountSnapshot, TransactionError> {
+        match self.apply_to(ledger) {
+            // The spec says resolves and chargebacks of transactions which
+            // aren't disputed can be ignored. Both fail before any funds move.
+            Err(TransactionError::NotDisputed | TransactionError::TransactionNotFound)
+                if ledger.options.lenient_resolve
+                    && matches!(
+                        self.tx_type,
+                        TransactionType::Resolve | TransactionType::Chargeback
+                    ) =>
+            {
+                Ok(ledger.accounts.get(&self.client_id).map_or_else(
+                    || Account::new(self.client_id).snapshot(),
+                    Account::snapshot,
+                ))
+            }
+            result => {
+                // An unbalanced chargeback is reported but still applied.
+                if matches!(
+                    result,
+                    Ok(_) | Err(TransactionError::UnbalancedChargeback { .. })
+                ) {
+                    ledger.mark_changed(self.client_id);
+                }
+
+                result
+            }
+        }
+    }
+
+    /// Undoes a deposit or withdrawal previously appended to the ledger,
+    /// moving back whatever it settled and forgetting it, so it can no
+    /// longer be disputed. Fails with `InsufficientFunds` rather than
+    /// leave the client's available funds negative, and with
+    /// `Irreversible` for disputes, resolves, chargebacks and closes.
+    #[must_use = "a transaction which couldn't be reversed is only reported by the returned error"]
+    pub fn reverse_from(&self, ledger: &mut Ledger) -> Result<AccountSnapshot, TransactionError> {
+        if !matches!(
+            self.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return Err(TransactionError::Irreversible);
+        }
+
+        let options = ledger.options;
+        let recorded = ledger
+            .transactions
+            .get(&self.tx_id)
+            .ok_or(TransactionError::TransactionNotFound)?;
+
+        if recorded.client_id != self.client_id || recorded.tx_type != self.tx_type {
+            return Err(TransactionError::Unauthorized);
+        }
+
+        if recorded.disputed {
+            return Err(TransactionError::Irreversible);
+        }
+
+        let settled = recorded.settled;
+        let account = self.get_or_create_account(&mut ledger.accounts);
+        ensure_unlocked(account, false)?;
+
+        if account.available_funds - settled < 0.0 {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        account.available_funds -= settled;
+        account.transaction_count = account.transaction_count.saturating_sub(1);
+        account.rebalance(options.track_shortfall);
+        if let Some(precision) = options.precision {
+            account.round_funds(precision, options.rounding);
+        }
+
+        let snapshot = account.snapshot();
+        ledger.transactions.remove(&self.tx_id);
+        ledger.mark_changed(self.client_id);
+
+        Ok(snapshot)
+    }
+
+    fn apply_to(&self, ledger: &mut Ledger) -> Result<AccountSnapshot, TransactionError> {
+        // Checked before recording so that the transaction can't be
+        // disputed, and no account is created, for an unknown client.
+        if let Some(registered) = &ledger.registered_clients {
+            if !registered.contains(&self.client_id) {
+                return Err(TransactionError::UnknownClient);
+            }
+        }
+
+        if let Some(hook) = &ledger.hook {
+            match ledger.accounts.get(&self.client_id) {
+                Some(account) => hook.before_apply(self, account)?,
+                None => hook.before_apply(self, &Account::new(self.client_id))?,
             }
+        }
+
+        let options = ledger.options;
+
+        if options.strict_dispute_amounts
+            && self.amount.is_some()
+            && !matches!(
+                self.tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            return Err(TransactionError::Malformed);
+        }
+
+        let account = match self.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => return self.record(ledger),
+            TransactionType::Close => return self.close(ledger),
             TransactionType::Dispute => {
-                let account = self.get_account(&mut ledger.accounts)?;
+                let account = self.get_or_create_account(&mut ledger.accounts);
+                ensure_unlocked(account, false)?;
                 let referenced_tx = self.get_referenced_tx(&mut ledger.transactions)?;
                 let amount = referenced_tx.get_amount()?;
                 referenced_tx.is_not_disputed()?;
 
+                if options
+                    .max_open_disputes
+                    .is_some_and(|max| account.open_disputes >= max)
+                {
+                    return Err(TransactionError::TooManyOpenDisputes);
+                }
+
                 referenced_tx.disputed = true;
+                account.open_disputes += 1;
 
                 if referenced_tx.tx_type == TransactionType::Deposit {
                     account.available_funds -= amount;
                 }
 
                 account.held_funds += amount;
-                account.total_funds = account.available_funds + account.held_funds;
+                account.rebalance(options.track_shortfall);
+                if let Some(precision) = options.precision {
+                    account.round_funds(precision, options.rounding);
+                }
+
+                account
             }
             TransactionType::Resolve => {
-                let account = self.get_account(&mut ledger.accounts)?;
+                let account = self.get_or_create_account(&mut ledger.accounts);
+                ensure_settleable(account)?;
                 let referenced_tx = self.get_referenced_tx(&mut ledger.transactions)?;
                 let amount = referenced_tx.get_amount()?;
                 referenced_tx.is_disputed()?;
 
+                // Both cases release the held funds to available funds.
+                // A disputed deposit had its funds moved from available funds
+                // so this restores them. A disputed withdrawal left available
+                // funds alone so this reimburses the client, see the README.
                 referenced_tx.disputed = false;
+                if referenced_tx.tx_type == TransactionType::Withdrawal {
+                    referenced_tx.settled += amount;
+                }
+                account.open_disputes = account.open_disputes.saturating_sub(1);
                 account.available_funds += amount;
                 account.held_funds -= amount;
-                account.total_funds = account.available_funds + account.held_funds;
+                account.rebalance(options.track_shortfall);
+                if let Some(precision) = options.precision {
+                    account.round_funds(precision, options.rounding);
+                }
+
+                account
             }
             TransactionType::Chargeback => {
-                let account = self.get_account(&mut ledger.accounts)?;
+                let account = self.get_or_create_account(&mut ledger.accounts);
+                ensure_settleable(account)?;
                 let referenced_tx = self.get_referenced_tx(&mut ledger.transactions)?;
                 let amount = referenced_tx.get_amount()?;
                 referenced_tx.is_disputed()?;
 
+                // Held funds for a disputed deposit were taken from available
+                // funds, so a negative available balance means the client
+                // already spent the funds being charged back.
+                if options.reject_uncovered_chargeback
+                    && referenced_tx.tx_type == TransactionType::Deposit
+                    && account.available_funds - account.shortfall < 0.0
+                {
+                    return Err(TransactionError::UncoveredChargeback);
+                }
+
+                let held_before = account.held_funds;
+
                 referenced_tx.disputed = false;
-                account.is_locked = true;
+                if referenced_tx.tx_type == TransactionType::Deposit {
+                    referenced_tx.settled -= amount;
+                }
+                account.open_disputes = account.open_disputes.saturating_sub(1);
+                account.status = AccountStatus::Locked;
+                account.locked_by_tx = Some(self.tx_id);
                 account.held_funds -= amount;
-                account.total_funds = account.available_funds + account.held_funds;
+                account.rebalance(options.track_shortfall);
+                if let Some(precision) = options.precision {
+                    account.round_funds(precision, options.rounding);
+                }
+
+                if options.verify_chargebacks {
+                    let released = held_before - account.held_funds;
+
+                    if account.held_funds < -VERIFY_EPSILON
+                        || libm::fabs(released - amount) > VERIFY_EPSILON
+                    {
+                        return Err(TransactionError::UnbalancedChargeback {
+                            held: account.held_funds,
+                            released,
+                            disputed: amount,
+                        });
+                    }
+                }
+
+                account
             }
-        }
+        };
 
-        Ok(())
+        self.freeze_if_negative(account, options);
+
+        Ok(account.snapshot())
     }
 }
@@ -0,0 +1,26 @@
+use std::collections::{BTreeMap, HashMap};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{ledger::Ledger, output};
+
+/// Processes a CSV string of transactions and returns the resulting
+/// accounts as a CSV string, without touching the filesystem or stdout.
+#[wasm_bindgen]
+pub fn process_csv_string(input: &str) -> Result<String, JsValue> {
+    let mut ledger = Ledger::new(HashMap::new(), BTreeMap::new());
+
+    ledger
+        .process(input.as_bytes())
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+    let mut accounts = vec![];
+    output::write_csv(
+        ledger.export_accounts(),
+        &mut accounts,
+        csv::QuoteStyle::Necessary,
+    )
+    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+
+    String::from_utf8(accounts).map_err(|err| JsValue::from_str(&err.to_string()))
+}
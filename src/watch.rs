@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::Duration,
+};
+
+use notify::{RecursiveMode, Watcher};
+use toy_ledger::ledger::Ledger;
+
+/// File in a watched directory listing the files already processed, one
+/// name per line, so they're skipped when watching again.
+pub const PROCESSED_LIST: &str = ".processed";
+
+/// How often `watch` checks whether it's been asked to stop while waiting
+/// for files.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Names of the files in a watched directory which have been processed,
+/// kept in its `PROCESSED_LIST`.
+#[derive(Debug)]
+pub struct ProcessedFiles {
+    names: BTreeSet<String>,
+    list: File,
+}
+
+impl ProcessedFiles {
+    /// Reads the files processed so far in `dir`, creating its list if
+    /// there isn't one.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(PROCESSED_LIST);
+        let list = OpenOptions::new().create(true).append(true).open(&path)?;
+        let names = BufReader::new(File::open(&path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ProcessedFiles { names, list })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    fn record(&mut self, name: String) -> std::io::Result<()> {
+        writeln!(self.list, "{}", name)?;
+        self.list.flush()?;
+        self.names.insert(name);
+
+        Ok(())
+    }
+}
+
+/// Processes each `.csv` file in `dir` not processed yet, in name order,
+/// passing it to `apply` and then calling `emit` with the ledger. A file
+/// which `apply` fails on is reported and counted as processed, keeping
+/// the rows before the one which failed. Returns how many files were
+/// processed.
+pub fn process_new<A, F>(
+    dir: &Path,
+    ledger: &mut Ledger,
+    processed: &mut ProcessedFiles,
+    apply: &mut A,
+    emit: &mut F,
+) -> Result<usize, Box<dyn Error>>
+where
+    A: FnMut(&mut Ledger, File) -> Result<(), Box<dyn Error>>,
+    F: FnMut(&mut Ledger),
+{
+    let mut names = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        if path.is_file()
+            && path.extension().is_some_and(|ext| ext == "csv")
+            && !processed.contains(name)
+        {
+            names.push(name.to_string());
+        }
+    }
+
+    names.sort();
+
+    for name in &names {
+        if let Err(err) = apply(ledger, File::open(dir.join(name))?) {
+            eprintln!("Failed to process {}: {}", name, err);
+        }
+
+        processed.record(name.clone())?;
+        emit(ledger);
+    }
+
+    Ok(names.len())
+}
+
+/// Processes the `.csv` files dropped into `dir` with `apply`, first those
+/// already there and then each one as it appears. Files named
+/// in the directory's `PROCESSED_LIST` are skipped. Files should be moved
+/// into the directory once complete, eg. written as `name.csv.tmp` and
+/// then renamed, or a partial file may be read. Returns once `stop` is
+/// set, which is checked after each scan of the directory, or if watching
+/// or reading fails.
+pub fn watch<A, F>(
+    dir: &Path,
+    ledger: &mut Ledger,
+    stop: &AtomicBool,
+    mut apply: A,
+    mut emit: F,
+) -> Result<(), Box<dyn Error>>
+where
+    A: FnMut(&mut Ledger, File) -> Result<(), Box<dyn Error>>,
+    F: FnMut(&mut Ledger),
+{
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    // Watching before the first scan so files dropped during it are seen.
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    let mut processed = ProcessedFiles::open(dir)?;
+    process_new(dir, ledger, &mut processed, &mut apply, &mut emit)?;
+
+    while !stop.load(Ordering::SeqCst) {
+        match receiver.recv_timeout(STOP_POLL_INTERVAL) {
+            // The directory is rescanned rather than following the event's
+            // paths, which catches files moved in as well as those created.
+            Ok(event) => {
+                event?;
+                process_new(dir, ledger, &mut processed, &mut apply, &mut emit)?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Ok(())
+}